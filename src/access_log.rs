@@ -0,0 +1,336 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Instant;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use log::error;
+use serde_json::json;
+use tokio::fs::OpenOptions;
+use tokio::io::{stdout, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+// 访问日志格式
+#[derive(Debug, Copy, Clone)]
+pub enum AccessLogFormat {
+    Json,
+    Combined,
+}
+
+#[derive(Debug)]
+pub struct InvalidAccessLogFormat;
+
+impl Display for InvalidAccessLogFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("must be json or combined", f)
+    }
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = InvalidAccessLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(AccessLogFormat::Json),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(InvalidAccessLogFormat),
+        }
+    }
+}
+
+// 转发结果
+#[derive(Debug, Copy, Clone)]
+pub enum Outcome {
+    Forwarded,       // 已转发
+    BadGateway,      // 502
+    Unauthorized,    // 401
+    Forbidden,       // 403
+    Unavailable,     // 503
+    NotFound,        // 404
+    TooManyRequests, // 429
+    GatewayTimeout,  // 504, 等待客户端应答超时
+    Offline,         // 503, 已知域名的客户端未连接
+    OfflineRedirect, // 302, 已知域名的客户端未连接, 跳转到指定地址
+    UnknownDomain,   // 404, 未知域名
+    Timeout,         // 解析请求超时
+    Invalid,         // 请求格式错误、请求头过长或访问者提前断开
+    IdleTimeout,     // 已转发, 转发连接空闲超时
+    ForwardError,    // 已转发, 转发过程中连接出错
+}
+
+impl Outcome {
+    fn status(&self) -> Option<u16> {
+        match self {
+            Outcome::Forwarded
+            | Outcome::Timeout
+            | Outcome::Invalid
+            | Outcome::IdleTimeout
+            | Outcome::ForwardError => None,
+            Outcome::BadGateway => Some(502),
+            Outcome::Unauthorized => Some(401),
+            Outcome::Forbidden => Some(403),
+            Outcome::Unavailable => Some(503),
+            Outcome::NotFound => Some(404),
+            Outcome::TooManyRequests => Some(429),
+            Outcome::GatewayTimeout => Some(504),
+            Outcome::Offline => Some(503),
+            Outcome::OfflineRedirect => Some(302),
+            Outcome::UnknownDomain => Some(404),
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Outcome::Forwarded => "forwarded",
+            Outcome::BadGateway => "502",
+            Outcome::Unauthorized => "401",
+            Outcome::Forbidden => "403",
+            Outcome::Unavailable => "503",
+            Outcome::NotFound => "404",
+            Outcome::TooManyRequests => "429",
+            Outcome::GatewayTimeout => "504",
+            Outcome::Timeout => "timeout",
+            Outcome::Invalid => "invalid",
+            Outcome::IdleTimeout => "idle_timeout",
+            Outcome::ForwardError => "forward_error",
+            Outcome::Offline => "offline",
+            Outcome::OfflineRedirect => "offline_redirect",
+            Outcome::UnknownDomain => "unknown_domain",
+        };
+        Display::fmt(s, f)
+    }
+}
+
+// 一条访问日志
+pub struct Entry {
+    time: DateTime<Local>,
+    start: Instant,
+    pub visitor: SocketAddr,        // 访问者地址
+    pub local: SocketAddr,          // 访问者连接的服务端地址
+    pub target: Option<String>,     // 转发目标, 域名或 "tcp:端口"
+    pub sni: Option<String>,        // TLS 握手中的 SNI
+    pub host: Option<String>,       // Host 头
+    pub method: Option<String>,     // 请求方法
+    pub path: Option<String>,       // 请求路径
+    pub version: Option<String>,    // 协议版本
+    pub referer: Option<String>,    // Referer 头
+    pub user_agent: Option<String>, // User-Agent 头
+    pub client: Option<String>,     // 处理该连接的客户端证书 subject
+    pub received: u64,              // 从访问者收到的字节数
+    pub sent: u64,                  // 发给访问者的字节数
+    pub outcome: Outcome,
+    pub request_id: Option<String>, // 请求 ID, 与错误响应的 X-Request-Id 头相同
+}
+
+impl Entry {
+    pub fn new(visitor: SocketAddr, local: SocketAddr) -> Self {
+        Self {
+            time: Local::now(),
+            start: Instant::now(),
+            visitor,
+            local,
+            target: None,
+            sni: None,
+            host: None,
+            method: None,
+            path: None,
+            version: None,
+            referer: None,
+            user_agent: None,
+            client: None,
+            received: 0,
+            sent: 0,
+            outcome: Outcome::Forwarded,
+            request_id: None,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        json!({
+            "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+            "visitor": self.visitor.to_string(),
+            "local": self.local.to_string(),
+            "target": self.target,
+            "sni": self.sni,
+            "host": self.host,
+            "method": self.method,
+            "path": self.path,
+            "version": self.version,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "client": self.client,
+            "duration": self.start.elapsed().as_secs_f64(),
+            "received": self.received,
+            "sent": self.sent,
+            "outcome": self.outcome.to_string(),
+            "request_id": self.request_id,
+        })
+        .to_string()
+    }
+
+    // combined 格式, 末尾追加转发目标、客户端证书 subject、耗时、转发结果和请求 ID
+    fn to_combined(&self) -> String {
+        let request = match (&self.method, &self.path, &self.version) {
+            (Some(method), Some(path), Some(version)) => {
+                format!("{} {} {}", method, path, version)
+            }
+            _ => "-".to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" \"{}\" \"{}\" {:.3} {} {}",
+            self.visitor.ip(),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&request),
+            self.outcome
+                .status()
+                .map_or("-".to_string(), |v| v.to_string()),
+            self.sent,
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+            escape(self.target.as_deref().unwrap_or("-")),
+            escape(self.client.as_deref().unwrap_or("-")),
+            self.start.elapsed().as_secs_f64(),
+            self.outcome,
+            self.request_id.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// 访问日志, 由单独的任务写入, 与 env_logger 的输出分开
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    tx: Option<UnboundedSender<String>>,
+}
+
+impl AccessLog {
+    // 不记录访问日志
+    pub fn disabled() -> Self {
+        Self {
+            format: AccessLogFormat::Json,
+            tx: None,
+        }
+    }
+
+    // path 为 "-" 时输出到标准输出, 否则追加到文件
+    pub async fn open(path: &str, format: AccessLogFormat) -> crate::Result<Self> {
+        let (tx, rx) = unbounded_channel();
+        if path == "-" {
+            tokio::spawn(write_lines(stdout(), rx));
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .map_err(err!("cannot open {}", path))?;
+            tokio::spawn(write_lines(file, rx));
+        }
+        Ok(Self {
+            format,
+            tx: Some(tx),
+        })
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        if let Some(tx) = &self.tx {
+            let line = match self.format {
+                AccessLogFormat::Json => entry.to_json(),
+                AccessLogFormat::Combined => entry.to_combined(),
+            };
+            let _ = tx.send(line);
+        }
+    }
+}
+
+async fn write_lines(mut writer: impl AsyncWrite + Unpin, mut rx: UnboundedReceiver<String>) {
+    while let Some(mut line) = rx.recv().await {
+        line.push('\n');
+        let write = async {
+            writer.write_all(line.as_bytes()).await?;
+            writer.flush().await
+        };
+        if let Err(err) = write.await {
+            error!("write access log error: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn entry() -> Entry {
+        let mut entry = Entry::new(
+            "[2001:db8::1]:50000".parse().unwrap(),
+            "10.0.0.1:443".parse().unwrap(),
+        );
+        entry.target = Some("a.com".to_string());
+        entry.host = Some("a.com".to_string());
+        entry.method = Some("GET".to_string());
+        entry.path = Some("/a\"b\\c".to_string());
+        entry.version = Some("HTTP/1.1".to_string());
+        entry.user_agent = Some("curl \"x\"".to_string());
+        entry.client = Some("CN=client".to_string());
+        entry.received = 100;
+        entry.sent = 200;
+        entry.request_id = Some("0123456789abcdef".to_string());
+        entry
+    }
+
+    #[test]
+    fn json() {
+        let mut entry = entry();
+        entry.outcome = Outcome::IdleTimeout;
+        let v: Value = serde_json::from_str(&entry.to_json()).unwrap();
+        assert_eq!(v["visitor"], "[2001:db8::1]:50000");
+        assert_eq!(v["local"], "10.0.0.1:443");
+        assert_eq!(v["path"], "/a\"b\\c");
+        assert_eq!(v["user_agent"], "curl \"x\"");
+        assert_eq!(v["referer"], Value::Null);
+        assert_eq!(v["client"], "CN=client");
+        assert_eq!(v["received"], 100);
+        assert_eq!(v["sent"], 200);
+        assert_eq!(v["outcome"], "idle_timeout");
+        assert_eq!(v["request_id"], "0123456789abcdef");
+    }
+
+    #[test]
+    fn combined() {
+        let mut entry = entry();
+        entry.outcome = Outcome::BadGateway;
+        let line = entry.to_combined();
+        let time = entry.time.format("%d/%b/%Y:%H:%M:%S %z");
+        let prefix = format!(
+            "2001:db8::1 - - [{}] \"GET /a\\\"b\\\\c HTTP/1.1\" 502 200 \"-\" \"curl \\\"x\\\"\" \
+             \"a.com\" \"CN=client\" ",
+            time
+        );
+        assert!(line.starts_with(&prefix), "{}", line);
+        assert!(line.ends_with(" 502 0123456789abcdef"), "{}", line);
+    }
+
+    #[test]
+    fn combined_without_request() {
+        let mut entry = Entry::new(
+            "10.0.0.2:50000".parse().unwrap(),
+            "10.0.0.1:443".parse().unwrap(),
+        );
+        entry.outcome = Outcome::Invalid;
+        let line = entry.to_combined();
+        assert!(
+            line.contains("] \"-\" - 0 \"-\" \"-\" \"-\" \"-\" "),
+            "{}",
+            line
+        );
+        assert!(line.ends_with(" invalid -"), "{}", line);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::read_to_string;
+use std::net::IpAddr;
+
+use crate::cidr::{self, Cidr};
+
+// 访问者地址的访问控制列表, 从文件读取, 每行格式为 "[域名] allow|deny 地址段 [地址段...]",
+// 不带域名的行对所有域名生效, 忽略空行和 # 开头的行.
+//
+// 地址匹配任一 deny 规则 (全局或域名) 时拒绝; 域名配置了 allow 规则时地址需匹配其中之一,
+// 否则全局配置了 allow 规则时地址需匹配全局 allow 规则
+#[derive(Debug, Default)]
+pub struct Acl {
+    global: Rule,
+    domains: HashMap<String, Rule>,
+}
+
+#[derive(Debug, Default)]
+struct Rule {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+#[derive(Debug)]
+struct InvalidAcl(usize);
+
+impl Display for InvalidAcl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}: expect \"[domain] allow|deny cidr [cidr...]\"",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidAcl {}
+
+impl Acl {
+    pub fn load(path: &str) -> crate::Result<Self> {
+        let content = read_to_string(path).map_err(err!("cannot open {}", path))?;
+        Self::parse(&content).map_err(err!("{}", path))
+    }
+
+    fn parse(content: &str) -> Result<Self, InvalidAcl> {
+        let mut acl = Acl::default();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (rule, action) = match words.next() {
+                Some(action @ ("allow" | "deny")) => (&mut acl.global, Some(action)),
+                Some(domain) => {
+                    let rule = acl.domains.entry(domain.to_string()).or_default();
+                    (rule, words.next())
+                }
+                None => continue,
+            };
+            let list = match action {
+                Some("allow") => &mut rule.allow,
+                Some("deny") => &mut rule.deny,
+                _ => return Err(InvalidAcl(i + 1)),
+            };
+            let start = list.len();
+            for v in words {
+                list.push(v.parse().map_err(|_| InvalidAcl(i + 1))?);
+            }
+            if list.len() == start {
+                return Err(InvalidAcl(i + 1));
+            }
+        }
+        Ok(acl)
+    }
+
+    // 是否允许 ip 访问 domain
+    pub fn allows(&self, domain: &str, ip: IpAddr) -> bool {
+        let rule = self.domains.get(domain);
+        if cidr::contains(&self.global.deny, ip)
+            || rule.is_some_and(|v| cidr::contains(&v.deny, ip))
+        {
+            return false;
+        }
+        let allow = match rule {
+            Some(rule) if !rule.allow.is_empty() => &rule.allow,
+            _ => &self.global.allow,
+        };
+        allow.is_empty() || cidr::contains(allow, ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(acl: &str, domain: &str, ip: &str) -> bool {
+        Acl::parse(acl).unwrap().allows(domain, ip.parse().unwrap())
+    }
+
+    #[test]
+    fn precedence() {
+        let acl = "\
+            deny 10.0.0.1\n\
+            allow 10.0.0.0/8\n\
+            a.com deny 10.0.0.2\n\
+            a.com allow 10.0.0.1 192.168.0.0/16\n\
+            b.com deny 10.0.0.3\n";
+
+        // 全局 deny 优先于域名 allow
+        assert!(!allows(acl, "a.com", "10.0.0.1"));
+        // 域名 deny 优先于全局 allow
+        assert!(!allows(acl, "a.com", "10.0.0.2"));
+        // 域名配置了 allow 时不再使用全局 allow
+        assert!(allows(acl, "a.com", "192.168.1.1"));
+        assert!(!allows(acl, "a.com", "10.0.0.4"));
+        // 域名只配置了 deny 时使用全局 allow
+        assert!(!allows(acl, "b.com", "10.0.0.3"));
+        assert!(allows(acl, "b.com", "10.0.0.4"));
+        assert!(!allows(acl, "b.com", "192.168.1.1"));
+        // 未配置的域名只受全局规则限制
+        assert!(!allows(acl, "c.com", "10.0.0.1"));
+        assert!(allows(acl, "c.com", "10.0.0.2"));
+        assert!(!allows(acl, "c.com", "::1"));
+    }
+
+    #[test]
+    fn no_allow_rules() {
+        let acl = "a.com deny ::1\n";
+        assert!(!allows(acl, "a.com", "::1"));
+        assert!(allows(acl, "a.com", "10.0.0.1"));
+        assert!(allows(acl, "b.com", "::1"));
+        assert!(allows("", "a.com", "::1"));
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(Acl::parse("# comment\n\n  allow 10.0.0.0/8  \n").is_ok());
+        assert_eq!(Acl::parse("allow\n").unwrap_err().0, 1);
+        assert_eq!(Acl::parse("\na.com permit 10.0.0.1\n").unwrap_err().0, 2);
+        assert_eq!(Acl::parse("a.com\n").unwrap_err().0, 1);
+        assert_eq!(Acl::parse("deny 10.0.0.0/33\n").unwrap_err().0, 1);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fs::remove_file;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::SecondsFormat;
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::http::{read_request, Status, BAD_REQUEST, NOT_FOUND, OK, UNAUTHORIZED};
+use crate::shared::{Session, Shared};
+use crate::util::eq_token;
+
+const METHOD_NOT_ALLOWED: Status = Status::new(405, "Method Not Allowed");
+
+const INTERNAL_SERVER_ERROR: Status = Status::new(500, "Internal Server Error");
+
+// 读取请求的超时时间, 避免连接一直不发送完整的请求头
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 管理接口
+//
+// GET  /clients                   已注册的客户端
+// GET  /domains                   已注册和被禁止的域名, 以及各域名正在转发的连接数
+// POST /clients/{id}/kick         断开客户端
+// POST /domains/{domain}/block    禁止域名
+// POST /domains/{domain}/unblock  解除禁止
+// POST /domains/{domain}/drain    排空域名, 新连接返回 503
+// POST /domains/{domain}/undrain  取消排空
+// POST /reload                    重新加载配置
+//
+// 请求需要带 "Authorization: Bearer <token>" 头
+pub async fn serve(addr: SocketAddr, token: String, shared: Shared) -> crate::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(err!("cannot bind {}", addr))?;
+    info!("admin started at {}", addr);
+    let token = Arc::new(token);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("admin connection from {}", addr);
+                    let token = token.clone();
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_admin(stream, Some(&token), &shared).await {
+                            error!("{}", e);
+                        }
+                    });
+                }
+                Err(err) => error!("admin accept error: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+// 在 unix 域 socket 上提供管理接口, 不需要 token
+pub async fn serve_unix(path: &Path, shared: Shared) -> crate::Result<()> {
+    // 删除上次运行留下的 socket 文件
+    if path.exists() {
+        remove_file(path).map_err(err!("cannot remove {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path).map_err(err!("cannot bind {}", path.display()))?;
+    info!("admin started at {}", path.display());
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    debug!("admin connection from unix socket");
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_admin(stream, None, &shared).await {
+                            error!("{}", e);
+                        }
+                    });
+                }
+                Err(err) => error!("admin accept error: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+// token 为 None 时不检查 Authorization 头
+async fn handle_admin(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    token: Option<&str>,
+    shared: &Shared,
+) -> crate::Result<()> {
+    let request = timeout(READ_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(err!("read admin request timeout"))??;
+    let authorized = match token {
+        Some(token) => request
+            .header("authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| eq_token(v.trim(), token)),
+        None => true,
+    };
+    if !authorized {
+        return reply(&mut stream, UNAUTHORIZED, json!({"error": "unauthorized"})).await;
+    }
+
+    let (method, path) = match request.request_line() {
+        Some((method, path, _)) => (method, path),
+        None => return reply(&mut stream, BAD_REQUEST, json!({"error": "bad request"})).await,
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|v| !v.is_empty()).collect();
+    let (status, body) = match (method, segments.as_slice()) {
+        ("GET", ["clients"]) => (OK, clients(shared)),
+        ("GET", ["domains"]) => (OK, domains(shared)),
+        ("POST", ["clients", id, "kick"]) => kick(shared, id),
+        ("POST", ["domains", domain, "block"]) => {
+            info!("block domain {}", domain);
+            shared.client.block(domain);
+            (OK, json!({"domain": domain, "blocked": true}))
+        }
+        ("POST", ["domains", domain, "unblock"]) => {
+            info!("unblock domain {}", domain);
+            shared.client.unblock(domain);
+            (OK, json!({"domain": domain, "blocked": false}))
+        }
+        ("POST", ["domains", domain, "drain"]) => {
+            if shared.client.drain(domain) {
+                info!("drain domain {}", domain);
+                (OK, domain_json(shared, domain))
+            } else {
+                (NOT_FOUND, json!({"error": "domain not registered"}))
+            }
+        }
+        ("POST", ["domains", domain, "undrain"]) => {
+            info!("undrain domain {}", domain);
+            shared.client.undrain(domain);
+            (OK, domain_json(shared, domain))
+        }
+        ("POST", ["reload"]) => reload(shared).await,
+        (_, ["clients"] | ["domains"] | ["reload"] | [_, _, _]) => {
+            (METHOD_NOT_ALLOWED, json!({"error": "method not allowed"}))
+        }
+        _ => (NOT_FOUND, json!({"error": "not found"})),
+    };
+    reply(&mut stream, status, body).await
+}
+
+async fn reply(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    status: Status,
+    body: Value,
+) -> crate::Result<()> {
+    status.send_json(stream, &body.to_string()).await?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+fn clients(shared: &Shared) -> Value {
+    let clients: Vec<_> = shared.client.sessions().iter().map(session_json).collect();
+    Value::Array(clients)
+}
+
+fn session_json(session: &Arc<Session>) -> Value {
+    let last_ping = *session.last_ping.lock().unwrap();
+    json!({
+        "id": session.id,
+        "addr": session.addr.to_string(),
+        "subject": session.subject,
+        "domains": session.domains,
+        "ports": session.ports,
+        "udp_ports": session.udp_ports,
+        "connected_at": session.connected_at.to_rfc3339_opts(SecondsFormat::Secs, false),
+        "last_ping": last_ping.map(|v| v.to_rfc3339_opts(SecondsFormat::Secs, false)),
+    })
+}
+
+fn domains(shared: &Shared) -> Value {
+    let active = active(shared);
+    let mut domains = BTreeMap::new();
+    for session in shared.client.sessions() {
+        let targets = session
+            .domains
+            .iter()
+            .cloned()
+            .chain(session.ports.iter().map(|v| format!("tcp:{}", v)));
+        for name in targets {
+            let value = target_json(shared, &name, Some(&session), &active);
+            domains.insert(name, value);
+        }
+    }
+    for name in shared.client.blocked() {
+        let value = target_json(shared, &name, None, &active);
+        domains.entry(name).or_insert(value);
+    }
+    Value::Array(domains.into_values().collect())
+}
+
+fn domain_json(shared: &Shared, domain: &str) -> Value {
+    let session = shared
+        .client
+        .sessions()
+        .into_iter()
+        .find(|v| v.domains.iter().any(|d| d == domain));
+    target_json(shared, domain, session.as_ref(), &active(shared))
+}
+
+fn target_json(
+    shared: &Shared,
+    name: &str,
+    session: Option<&Arc<Session>>,
+    active: &BTreeMap<String, i64>,
+) -> Value {
+    json!({
+        "domain": name,
+        "client": session.map(|v| v.id),
+        "addr": session.map(|v| v.addr.to_string()),
+        "active": active.get(name).copied().unwrap_or(0),
+        "blocked": shared.client.is_blocked(name),
+        "draining": shared.client.is_draining(name),
+    })
+}
+
+// 各转发目标正在转发的连接数, 包括 TCP 端口
+fn active(shared: &Shared) -> BTreeMap<String, i64> {
+    shared
+        .metrics
+        .active
+        .values()
+        .into_iter()
+        .map(|(labels, v)| (labels[0].clone(), v.get()))
+        .collect()
+}
+
+async fn reload(shared: &Shared) -> (Status, Value) {
+    let (tx, rx) = oneshot::channel();
+    if shared.reload.send(tx).is_err() {
+        return (INTERNAL_SERVER_ERROR, json!({"error": "server is exiting"}));
+    }
+    match rx.await {
+        Ok(Ok(())) => (OK, json!({"reloaded": true})),
+        Ok(Err(e)) => (INTERNAL_SERVER_ERROR, json!({"error": e})),
+        Err(_) => (INTERNAL_SERVER_ERROR, json!({"error": "server is exiting"})),
+    }
+}
+
+fn kick(shared: &Shared, id: &str) -> (Status, Value) {
+    let session = match id.parse().ok().and_then(|id| shared.client.session(id)) {
+        Some(session) => session,
+        None => return (NOT_FOUND, json!({"error": "client not found"})),
+    };
+    info!("kick client {} {}", session.id, session.addr);
+    session.kick.notify_one();
+    (OK, session_json(&session))
+}
@@ -0,0 +1,254 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::read_to_string;
+use std::str::{from_utf8, FromStr};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::util::eq_token;
+
+// 域名的访问认证方式, 访问者需带 Authorization 头
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Auth {
+    Basic(Vec<(String, String)>), // htpasswd 中的用户名和 bcrypt 哈希
+    Bearer(String),               // 固定 token
+}
+
+// 消息会以 debug 级别写入日志, 不输出哈希和 token
+impl Debug for Auth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::Basic(users) => f
+                .debug_tuple("Basic")
+                .field(&users.iter().map(|(name, _)| name).collect::<Vec<_>>())
+                .finish(),
+            Auth::Bearer(_) => f.write_str("Bearer(***)"),
+        }
+    }
+}
+
+impl Auth {
+    // 检查 Authorization 头, bcrypt 校验较慢, 在单独的线程中执行
+    pub async fn check(&self, authorization: Option<&str>) -> bool {
+        let (scheme, credentials) = match authorization.and_then(|v| v.split_once(' ')) {
+            Some((scheme, credentials)) => (scheme, credentials.trim()),
+            None => return false,
+        };
+        match self {
+            Auth::Basic(users) if scheme.eq_ignore_ascii_case("basic") => {
+                let decoded = match STANDARD.decode(credentials) {
+                    Ok(v) => v,
+                    Err(_) => return false,
+                };
+                let (user, password) =
+                    match from_utf8(&decoded).ok().and_then(|v| v.split_once(':')) {
+                        Some(v) => v,
+                        None => return false,
+                    };
+                let hash = match users.iter().find(|(name, _)| name == user) {
+                    Some((_, hash)) => hash.clone(),
+                    None => return false,
+                };
+                let password = password.to_string();
+                spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+                    .await
+                    .unwrap_or(false)
+            }
+            Auth::Bearer(token) if scheme.eq_ignore_ascii_case("bearer") => {
+                eq_token(credentials, token)
+            }
+            _ => false,
+        }
+    }
+
+    // 401 响应的 WWW-Authenticate 头
+    pub fn challenge(&self, domain: &str) -> String {
+        match self {
+            Auth::Basic(_) => format!("Basic realm=\"{}\", charset=\"UTF-8\"", domain),
+            Auth::Bearer(_) => format!("Bearer realm=\"{}\"", domain),
+        }
+    }
+}
+
+// 认证配置, 格式为 "域名:basic:htpasswd 文件" 或 "域名:bearer:token"
+#[derive(Debug)]
+pub struct AuthOption {
+    pub domain: String,
+    source: AuthSource,
+}
+
+#[derive(Debug)]
+enum AuthSource {
+    Basic(String), // htpasswd 文件路径
+    Bearer(String),
+}
+
+#[derive(Debug)]
+pub struct InvalidAuthOption;
+
+impl Display for InvalidAuthOption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("wrong format", f)
+    }
+}
+
+impl FromStr for AuthOption {
+    type Err = InvalidAuthOption;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (domain, kind, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(domain), Some(kind), Some(value)) if !domain.is_empty() && !value.is_empty() => {
+                (domain, kind, value)
+            }
+            _ => return Err(InvalidAuthOption),
+        };
+        let source = match kind {
+            "basic" => AuthSource::Basic(value.to_string()),
+            "bearer" => AuthSource::Bearer(value.to_string()),
+            _ => return Err(InvalidAuthOption),
+        };
+        Ok(Self {
+            domain: domain.to_string(),
+            source,
+        })
+    }
+}
+
+impl AuthOption {
+    // 读取 htpasswd 文件, 重新加载配置时再次调用
+    pub fn load(&self) -> crate::Result<Auth> {
+        match &self.source {
+            AuthSource::Basic(path) => Ok(Auth::Basic(load_htpasswd(path)?)),
+            AuthSource::Bearer(token) => Ok(Auth::Bearer(token.clone())),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InvalidHtpasswd(usize);
+
+impl Display for InvalidHtpasswd {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: expect \"user:bcrypt hash\"", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHtpasswd {}
+
+// 每行格式为 "用户名:哈希", 只支持 bcrypt 哈希 (htpasswd -B 生成), 忽略空行和 # 开头的行
+fn load_htpasswd(path: &str) -> crate::Result<Vec<(String, String)>> {
+    let content = read_to_string(path).map_err(err!("cannot open {}", path))?;
+    parse_htpasswd(&content).map_err(err!("{}", path))
+}
+
+fn parse_htpasswd(content: &str) -> Result<Vec<(String, String)>, InvalidHtpasswd> {
+    let mut users = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((user, hash)) if !user.is_empty() && hash.starts_with("$2") => {
+                users.push((user.to_string(), hash.to_string()))
+            }
+            _ => return Err(InvalidHtpasswd(i + 1)),
+        }
+    }
+    Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(user: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", user, password))
+        )
+    }
+
+    #[test]
+    fn parse_auth_option() {
+        let option: AuthOption = "a.com:bearer:secret".parse().unwrap();
+        assert_eq!(option.domain, "a.com");
+        assert!(matches!(option.source, AuthSource::Bearer(ref v) if v == "secret"));
+        let option: AuthOption = "a.com:basic:/etc/htpasswd".parse().unwrap();
+        assert!(matches!(option.source, AuthSource::Basic(ref v) if v == "/etc/htpasswd"));
+        // token 中可以有冒号
+        let option: AuthOption = "a.com:bearer:a:b".parse().unwrap();
+        assert!(matches!(option.source, AuthSource::Bearer(ref v) if v == "a:b"));
+        for v in [
+            "a.com",
+            "a.com:bearer",
+            "a.com:bearer:",
+            ":bearer:x",
+            "a.com:digest:x",
+        ] {
+            assert!(v.parse::<AuthOption>().is_err(), "{}", v);
+        }
+    }
+
+    #[test]
+    fn parse_htpasswd_file() {
+        let content = "# users\n\nalice:$2y$05$abc\n  bob:$2b$05$def  \n";
+        let users = parse_htpasswd(content).unwrap();
+        assert_eq!(
+            users,
+            [
+                ("alice".to_string(), "$2y$05$abc".to_string()),
+                ("bob".to_string(), "$2b$05$def".to_string())
+            ]
+        );
+        // 只支持 bcrypt 哈希
+        let content = "alice:$2y$05$abc\nbob:$apr1$xyz$abc\n";
+        assert_eq!(parse_htpasswd(content).unwrap_err().0, 2);
+        assert_eq!(parse_htpasswd("bob:{SHA}abc").unwrap_err().0, 1);
+        assert_eq!(parse_htpasswd("bob").unwrap_err().0, 1);
+        assert_eq!(parse_htpasswd(":$2y$05$abc").unwrap_err().0, 1);
+    }
+
+    #[tokio::test]
+    async fn check_basic() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let auth = Auth::Basic(vec![("alice".to_string(), hash)]);
+        assert!(auth.check(Some(&basic("alice", "secret"))).await);
+        // scheme 不区分大小写
+        let lower = basic("alice", "secret").replace("Basic", "basic");
+        assert!(auth.check(Some(&lower)).await);
+        assert!(!auth.check(Some(&basic("alice", "wrong"))).await);
+        assert!(!auth.check(Some(&basic("bob", "secret"))).await);
+        assert!(!auth.check(Some("Basic !!!")).await);
+        assert!(
+            !auth
+                .check(Some(&format!("Basic {}", STANDARD.encode("alice"))))
+                .await
+        );
+        assert!(!auth.check(Some("Bearer secret")).await);
+        assert!(!auth.check(Some("Basic")).await);
+        assert!(!auth.check(None).await);
+    }
+
+    #[tokio::test]
+    async fn check_bearer() {
+        let auth = Auth::Bearer("secret".to_string());
+        assert!(auth.check(Some("Bearer secret")).await);
+        assert!(auth.check(Some("bearer  secret ")).await);
+        assert!(!auth.check(Some("Bearer secret2")).await);
+        assert!(!auth.check(Some("Bearer ")).await);
+        assert!(!auth.check(Some(&basic("secret", ""))).await);
+        assert!(!auth.check(None).await);
+    }
+
+    #[test]
+    fn debug_hides_secrets() {
+        let auth = Auth::Basic(vec![("alice".to_string(), "$2y$05$hash".to_string())]);
+        assert_eq!(format!("{:?}", auth), "Basic([\"alice\"])");
+        let auth = Auth::Bearer("secret".to_string());
+        assert_eq!(format!("{:?}", auth), "Bearer(***)");
+    }
+}
@@ -0,0 +1,11 @@
+use std::process::exit;
+
+use http_forward::ctl;
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = ctl::run().await {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
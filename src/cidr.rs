@@ -0,0 +1,159 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// 地址段, 格式为 "10.0.0.0/8"、"fd00::/8", 不带前缀长度时表示单个地址
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // IPv4 映射的 IPv6 地址按 IPv4 地址匹配
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidCidr;
+
+impl Display for InvalidCidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("wrong format", f)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let parsed: IpAddr = addr.trim().parse().map_err(|_| InvalidCidr)?;
+        let max = if parsed.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| InvalidCidr)?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(InvalidCidr);
+        }
+        // IPv4 映射的 IPv6 地址段转换为 IPv4 地址段, 前缀需覆盖 "::ffff:0:0/96"
+        let addr = canonical(parsed);
+        let prefix = match (parsed, addr) {
+            (IpAddr::V6(_), IpAddr::V4(_)) => prefix.checked_sub(96).ok_or(InvalidCidr)?,
+            _ => prefix,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+// 地址是否在任一地址段中
+pub fn contains(list: &[Cidr], ip: IpAddr) -> bool {
+    list.iter().any(|v| v.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr(" 10.1.2.3 ").to_string(), "10.1.2.3/32");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("::ffff:10.0.0.0/104").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("::ffff:10.0.0.1").to_string(), "10.0.0.1/32");
+        for s in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "::ffff:10.0.0.0/95",
+            "a.com",
+            "",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn prefix_zero_matches_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("0.0.0.0/0").contains(ip("::ffff:1.2.3.4")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn full_prefix_matches_single_address() {
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn partial_prefix() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.168.1.0/23").contains(ip("192.168.0.7")));
+        assert!(!cidr("192.168.1.0/24").contains(ip("192.168.0.7")));
+        assert!(cidr("fd00::/8").contains(ip("fdff::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("::ffff:10.0.0.0/104").contains(ip("11.0.0.1")));
+        assert!(contains(
+            &[cidr("fd00::/8"), cidr("10.0.0.1")],
+            ip("::ffff:10.0.0.1")
+        ));
+        assert!(!contains(&[], ip("10.0.0.1")));
+    }
+}
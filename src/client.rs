@@ -1,19 +1,29 @@
 use std::collections::HashMap;
+use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::process::exit;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use structopt::StructOpt;
-use tokio::io::{copy_bidirectional, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::time::{sleep, Duration};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{interval, sleep, timeout, Duration, Instant};
+use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
 use tokio_rustls::TlsConnector;
 
-use crate::protocol::{Protocol, Receiver, Request};
+use crate::auth::{Auth, AuthOption};
+use crate::destination::{Upstream, UpstreamOption};
+use crate::metrics::{self, Counter, Encoder, Family, Gauge, Histogram, Metered};
+use crate::protocol::{Protocol, Receiver, Request, Target, MAX_DATAGRAM_SIZE};
+use crate::timeout::copy_bidirectional;
 use crate::util::{init_logger, load_certs, load_key};
 
 // 命令行参数
@@ -23,10 +33,18 @@ struct Opt {
     #[structopt(short, long)]
     server_addr: String,
 
-    /// 转发配置，格式为"域名:转发地址"。示例："a.foo.com:127.0.0.1:80" 表示把对 a.foo.com 的请求转发到127.0.0.1:80
+    /// 转发配置，格式为"域名:转发地址"。示例："a.foo.com:127.0.0.1:80" 表示把对 a.foo.com 的请求转发到127.0.0.1:80，"a.foo.com:unix:/run/php-fpm.sock" 表示转发到 unix 域 socket。多个转发地址用 "," 分隔，每个连接轮流选择健康的地址，连接失败时尝试下一个。转发地址后可加参数，如 "a.foo.com:127.0.0.1:443?tls&sni=a.local&ca=ca.pem"，支持的参数：tls 使用 TLS 连接，sni=域名，ca=CA 证书，insecure 不校验证书，cert=客户端证书，key=客户端证书 key，check=健康检查方式（tcp 或 http:路径），interval=健康检查间隔秒数（默认 10），proxy=v1 或 v2 连接后先发送 PROXY protocol 头，把访问者地址传给目的地址，connect_timeout=连接超时秒数，idle_timeout=空闲超时秒数（覆盖 --connect-timeout 和 --idle-timeout）
     #[structopt(short, long)]
     forward: Vec<ForwardOption>,
 
+    /// TCP 转发配置，格式为"服务端端口:转发地址"，端口为 0 表示由服务端分配。示例："2222:127.0.0.1:22" 表示把服务端 2222 端口的连接转发到127.0.0.1:22
+    #[structopt(short, long)]
+    tcp: Vec<PortForwardOption<UpstreamOption>>,
+
+    /// UDP 转发配置，格式为"服务端端口:转发地址"，端口为 0 表示由服务端分配。示例："5353:127.0.0.1:53" 表示把服务端 5353 端口的数据报转发到127.0.0.1:53
+    #[structopt(short, long)]
+    udp: Vec<PortForwardOption<String>>,
+
     /// 客户端证书 key
     #[structopt(short = "k", long)]
     client_key: String,
@@ -34,6 +52,56 @@ struct Opt {
     /// 客户端证书
     #[structopt(short, long)]
     client_cert: String,
+
+    /// Prometheus 指标绑定地址，格式为 "ip:端口"，不设置则不提供指标
+    #[structopt(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// 收到 SIGTERM 后等待正在转发的连接结束的最长时间（秒），0 表示立即退出
+    #[structopt(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// 要求服务端对域名做访问认证，格式为 "域名:basic:htpasswd 文件"（只支持 bcrypt 哈希）或 "域名:bearer:token"，域名需在 --forward 中。服务端也配置了该域名的认证时以服务端为准
+    #[structopt(long)]
+    auth: Vec<AuthOption>,
+
+    /// 连接服务端（包括 TLS 握手）和目的地址的超时（秒），转发地址可用 connect_timeout 参数单独设置
+    #[structopt(long, default_value = "10")]
+    connect_timeout: u64,
+
+    /// 转发连接的空闲超时（秒），两个方向都没有数据超过该时间时关闭连接，0 表示不限制。转发地址可用 idle_timeout 参数单独设置
+    #[structopt(long, default_value = "0")]
+    idle_timeout: u64,
+
+    /// 向服务端发送 Ping 的间隔（秒），需小于服务端的 --client-idle-timeout
+    #[structopt(long, default_value = "60")]
+    ping_interval: u64,
+}
+
+// 与服务端断开后重连的间隔
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// 与服务端的连接结束的原因
+enum SessionEnd {
+    Exit,         // 收到 SIGINT, 立即退出
+    Shutdown,     // 收到 SIGTERM, 等待正在转发的连接结束后退出
+    Reconnect,    // 连接断开, 稍后重连
+    ReconnectNow, // 服务端即将退出, 立即重连
+}
+
+// 客户端配置, 重连时复用
+struct Context {
+    server_addr: String,
+    server_name: ServerName,
+    connector: TlsConnector,
+    domains: Vec<(String, Arc<Upstream>)>, // HTTP 转发
+    auth: Vec<(String, Auth)>,             // 要求服务端做的访问认证
+    tcp: Vec<(u16, Arc<Upstream>)>,        // TCP 转发, 端口为 0 表示由服务端分配
+    udp: Vec<(u16, String)>,               // UDP 转发, 端口为 0 表示由服务端分配
+    metrics: Metrics,
+    forwards: Arc<Gauge>,      // 正在进行的转发数
+    connect_timeout: Duration, // 连接服务端的超时
+    ping_interval: Duration,
 }
 
 pub async fn run() -> crate::Result<()> {
@@ -45,100 +113,452 @@ pub async fn run() -> crate::Result<()> {
 
     let server_name = ServerName::try_from(opt.server_addr.split(':').next().unwrap()).unwrap();
     let connector = create_connector(&opt)?;
-    let server_stream = TcpStream::connect(&opt.server_addr)
-        .await
-        .map_err(err!("cannot connect to {}", opt.server_addr))?;
-    let mut server_stream = connector
-        .connect(server_name.clone(), server_stream)
-        .await
-        .map_err(err!("cannot connect to {}", opt.server_addr))?;
 
-    let mut forward = HashMap::new();
+    let mut auth = Vec::with_capacity(opt.auth.len());
+    for v in &opt.auth {
+        auth.push((v.domain.clone(), v.load()?));
+    }
+    let connect_timeout = Duration::from_secs(opt.connect_timeout);
+    let idle_timeout = Duration::from_secs(opt.idle_timeout);
     let mut domains = Vec::with_capacity(opt.forward.len());
     for v in opt.forward {
-        domains.push(v.domain.clone());
-        forward.insert(v.domain, v.destination);
+        let upstream = Arc::new(Upstream::new(v.destination, connect_timeout, idle_timeout)?);
+        tokio::spawn(upstream.clone().run_health_check());
+        domains.push((v.domain, upstream));
     }
-    let msg = Protocol::Register { domains };
+    let mut tcp = Vec::with_capacity(opt.tcp.len());
+    for v in opt.tcp {
+        let upstream = Arc::new(Upstream::new(v.destination, connect_timeout, idle_timeout)?);
+        tokio::spawn(upstream.clone().run_health_check());
+        tcp.push((v.port, upstream));
+    }
+    let udp = opt
+        .udp
+        .into_iter()
+        .map(|v| (v.port, v.destination))
+        .collect();
+    let ctx = Arc::new(Context {
+        server_addr: opt.server_addr,
+        server_name,
+        connector,
+        domains,
+        auth,
+        tcp,
+        udp,
+        metrics: Metrics::new(),
+        forwards: Arc::new(Gauge::default()),
+        connect_timeout,
+        ping_interval: Duration::from_secs(opt.ping_interval),
+    });
+
+    if let Some(addr) = opt.metrics_addr {
+        let ctx = ctx.clone();
+        metrics::serve(addr, move || ctx.metrics.render()).await?;
+    }
+
+    // 首次连接或注册失败直接退出, 注册成功过之后断开则一直重连
+    let mut registered = false;
+    loop {
+        match handle_session(&ctx, &mut registered, &mut sig_int, &mut sig_term).await {
+            Ok(SessionEnd::Exit) => break,
+            Ok(SessionEnd::Shutdown) => {
+                shutdown(&ctx, opt.shutdown_timeout, &mut sig_int).await;
+                break;
+            }
+            Ok(SessionEnd::Reconnect) => info!("server closed"),
+            Ok(SessionEnd::ReconnectNow) => {
+                ctx.metrics.reconnects.inc();
+                continue;
+            }
+            Err(e) if registered => error!("{}", e),
+            Err(e) => return Err(e),
+        }
+
+        info!("reconnect in {} seconds", RECONNECT_DELAY.as_secs());
+        tokio::select! {
+            _ = sleep(RECONNECT_DELAY) => ctx.metrics.reconnects.inc(),
+            _ = sig_int.recv() => {
+                info!("catch SIGINT, exiting");
+                break;
+            }
+            _ = sig_term.recv() => {
+                info!("catch SIGTERM, shutting down");
+                shutdown(&ctx, opt.shutdown_timeout, &mut sig_int).await;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 等待正在转发的连接结束或超时
+async fn shutdown(ctx: &Context, timeout: u64, sig_int: &mut Signal) {
+    let deadline = sleep(Duration::from_secs(timeout));
+    tokio::pin!(deadline);
+    let mut tick = interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                if ctx.forwards.get() == 0 {
+                    info!("all forwards finished, exiting");
+                    break;
+                }
+            }
+            _ = &mut deadline => {
+                info!(
+                    "{} forwards still active after {} seconds, exiting",
+                    ctx.forwards.get(),
+                    timeout
+                );
+                break;
+            }
+            _ = sig_int.recv() => {
+                info!("catch SIGINT, exiting");
+                break;
+            }
+        }
+    }
+}
+
+// 连接服务端并处理消息直到连接断开
+async fn handle_session(
+    ctx: &Arc<Context>,
+    registered: &mut bool,
+    sig_int: &mut Signal,
+    sig_term: &mut Signal,
+) -> crate::Result<SessionEnd> {
+    let mut server_stream = connect_server(ctx).await?;
+
+    let msg = Protocol::Register {
+        domains: ctx.domains.iter().map(|v| v.0.clone()).collect(),
+        ports: ctx.tcp.iter().map(|v| v.0).collect(),
+        udp_ports: ctx.udp.iter().map(|v| v.0).collect(),
+        auth: ctx.auth.clone(),
+    };
     msg.send(&mut server_stream).await.map_err(err!())?;
 
+    // key 为转发目标, value 为目的地址
+    let mut forward: HashMap<_, _> = ctx
+        .domains
+        .iter()
+        .map(|(domain, upstream)| (Target::Domain(domain.clone()), upstream.clone()))
+        .collect();
+    // key 为 UDP 端口, value 为目的地址
+    let mut udp_forward = HashMap::new();
+    // key 为 (UDP 端口, 会话标识), value 用来把数据报交给对应的本地 socket
+    let mut udp_sessions: HashMap<(u16, u32), UnboundedSender<Vec<u8>>> = HashMap::new();
+    // 各转发任务发给服务端的消息
+    let (out_tx, mut out_rx) = unbounded_channel();
+
     let mut receiver = Receiver::new();
-    loop {
+    let mut ping = interval(ctx.ping_interval);
+    let mut ping_at = None;
+    let mut leaving = false;
+    let mut accepted = false; // 本次连接是否注册成功
+    let end = loop {
         tokio::select! {
             msg = receiver.recv(&mut server_stream) => {
                 match msg? {
-                    Some(Protocol::Ok) => {
+                    Some(Protocol::Ok { ports, udp_ports }) => {
                         info!("register ok");
+                        *registered = true;
+                        accepted = true;
+                        for (port, (_, v)) in ports.into_iter().zip(&ctx.tcp) {
+                            info!("tcp forward {} => {}", port, v);
+                            forward.insert(Target::Port(port), v.clone());
+                        }
+                        for (port, (_, v)) in udp_ports.into_iter().zip(&ctx.udp) {
+                            info!("udp forward {} => {}", port, v);
+                            udp_forward.insert(port, v.clone());
+                        }
                     }
                     Some(Protocol::Error) => {
                         error!("register error");
                         let _ = server_stream.shutdown().await;
-                        exit(1);
+                        if !*registered {
+                            exit(1);
+                        }
+                        break SessionEnd::Reconnect;
+                    }
+                    Some(Protocol::Pong) => {
+                        if let Some(at) = ping_at.take() {
+                            let rtt: Duration = Instant::now() - at;
+                            ctx.metrics.ping_rtt.observe(rtt.as_secs_f64());
+                        }
                     }
-                    Some(Protocol::Pong) => {}
                     Some(Protocol::Request(req)) => {
-                        let dst = forward.get(&req.domain).unwrap().clone();
-                        let server_name = server_name.clone();
-                        let server_addr = opt.server_addr.clone();
-                        let connector = connector.clone();
+                        let dst = match forward.get(&req.target) {
+                            Some(dst) => dst.clone(),
+                            None => {
+                                warn!("no forward for {}", req.target);
+                                Protocol::NotFound { key: req.key }.send(&mut server_stream).await?;
+                                continue;
+                            }
+                        };
+                        let ctx = ctx.clone();
+                        let out_tx = out_tx.clone();
                         tokio::spawn(async move {
-                            if let Err(e) = handle_forward(req, dst, server_addr, server_name, connector).await {
+                            if let Err(e) = handle_forward(req, dst, ctx, out_tx).await {
                                 error!("{}", e);
                             }
                         });
                     }
-                    Some(_) => {}
-                    None => {
-                        info!("server closed");
-                        break;
+                    Some(Protocol::UdpData { port, session, data }) => {
+                        let data = match udp_sessions.get(&(port, session)) {
+                            Some(tx) => match tx.send(data) {
+                                Ok(()) => continue,
+                                Err(e) => e.0,
+                            },
+                            None => data,
+                        };
+                        let dst = match udp_forward.get(&port) {
+                            Some(dst) => dst.clone(),
+                            None => continue,
+                        };
+                        let (tx, rx) = unbounded_channel();
+                        let _ = tx.send(data);
+                        udp_sessions.insert((port, session), tx);
+                        let out_tx = out_tx.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_udp_session(port, session, dst, rx, out_tx).await {
+                                error!("{}", e);
+                            }
+                        });
                     }
+                    Some(Protocol::UdpClose { port, session }) => {
+                        udp_sessions.remove(&(port, session));
+                    }
+                    // 注册后收到表示服务端即将退出, 注册时收到表示服务端正在退出
+                    Some(Protocol::GoingAway) if accepted => {
+                        info!("server going away, reconnecting");
+                        break SessionEnd::ReconnectNow;
+                    }
+                    Some(Protocol::GoingAway) => {
+                        info!("server is shutting down");
+                        break SessionEnd::Reconnect;
+                    }
+                    Some(_) => {}
+                    // 发送 GoingAway 后服务端关闭连接
+                    None if leaving => break SessionEnd::Shutdown,
+                    None => break SessionEnd::Reconnect,
                 }
             }
-            _ = sleep(Duration::from_secs(60)) => {
+            msg = out_rx.recv() => {
+                if let Some(msg) = msg {
+                    msg.send(&mut server_stream).await?;
+                }
+            }
+            _ = ping.tick() => {
                 Protocol::Ping.send(&mut server_stream).await.map_err(err!())?;
+                ping_at = Some(Instant::now());
             }
             _ = sig_int.recv() => {
                 info!("catch SIGINT, exiting");
-                break;
+                break SessionEnd::Exit;
             }
             _ = sig_term.recv() => {
-                info!("catch SIGTERM, exiting");
-                break;
+                if leaving {
+                    break SessionEnd::Shutdown;
+                }
+                // 通知服务端不再转发新连接, 继续处理已发出的转发请求直到服务端关闭连接
+                info!("catch SIGTERM, shutting down");
+                leaving = true;
+                Protocol::GoingAway.send(&mut server_stream).await.map_err(err!())?;
             }
         }
-    }
+    };
 
     let _ = server_stream.shutdown().await;
-    Ok(())
+    Ok(end)
+}
+
+async fn connect_server(ctx: &Context) -> crate::Result<TlsStream<TcpStream>> {
+    let connect = async {
+        let stream = TcpStream::connect(&ctx.server_addr)
+            .await
+            .map_err(err!("cannot connect to {}", ctx.server_addr))?;
+        ctx.connector
+            .connect(ctx.server_name.clone(), stream)
+            .await
+            .map_err(err!("cannot connect to {}", ctx.server_addr))
+    };
+    match timeout(ctx.connect_timeout, connect).await {
+        Ok(re) => re,
+        Err(e) => Err(e).map_err(err!("cannot connect to {}", ctx.server_addr)),
+    }
 }
 
 async fn handle_forward(
     req: Request,
-    destination: String,
-    server_addr: String,
-    server_name: ServerName,
-    connector: TlsConnector,
+    destination: Arc<Upstream>,
+    ctx: Arc<Context>,
+    out_tx: UnboundedSender<Protocol>,
 ) -> crate::Result<()> {
-    let mut dst_stream = TcpStream::connect(&destination)
-        .await
-        .map_err(err!("cannot connect to {}", destination))?;
-    let server_stream = TcpStream::connect(&server_addr)
-        .await
-        .map_err(err!("cannot connect to {}", server_addr))?;
-    let mut server_stream = connector
-        .connect(server_name, server_stream)
-        .await
-        .map_err(err!("cannot connect to {}", server_addr))?;
+    let _forward = ctx.forwards.track();
+    let name = req.target.to_string();
+    let metrics = &ctx.metrics;
+    let start = Instant::now();
+    let dst_stream = match destination.connect((req.visitor, req.local)).await {
+        Ok(stream) => {
+            let duration = start.elapsed().as_secs_f64();
+            metrics.connect_duration.get(&[&name]).observe(duration);
+            stream
+        }
+        Err(e) => {
+            metrics.connect_failures.get(&[&name]).inc();
+            // 通知服务端转发失败, 服务端立即响应访问者
+            let reason = match e.source() {
+                Some(source) => {
+                    format!("{}: cannot connect to destination: {}", req.target, source)
+                }
+                None => format!("{}: cannot connect to destination", req.target),
+            };
+            let _ = out_tx.send(Protocol::Reject {
+                key: req.key,
+                reason,
+            });
+            return Err(e);
+        }
+    };
+    let mut dst_stream = Metered::new(
+        dst_stream,
+        metrics.received_bytes.get(&[&name]),
+        metrics.sent_bytes.get(&[&name]),
+    );
+    let mut server_stream = connect_server(&ctx).await?;
 
     Protocol::Response { key: req.key }
         .send(&mut server_stream)
         .await
         .map_err(err!())?;
 
-    debug!("{} <=> {}", &req.domain, destination);
-    copy_bidirectional(&mut server_stream, &mut dst_stream)
+    let _active = metrics.active.get(&[&name]).track();
+    debug!("{} <=> {}", &req.target, destination);
+    copy_bidirectional(
+        &mut server_stream,
+        &mut dst_stream,
+        destination.idle_timeout(),
+    )
+    .await
+    .map_err(err!("{} <=> {}", &req.target, destination))?;
+    Ok(())
+}
+
+// 客户端指标
+struct Metrics {
+    reconnects: Counter,                 // 重连次数
+    ping_rtt: Histogram,                 // Ping 到 Pong 的耗时
+    active: Family<Gauge>,               // 各转发目标正在转发的连接数
+    connect_failures: Family<Counter>,   // 各转发目标连接目的地址失败数
+    connect_duration: Family<Histogram>, // 各转发目标连接目的地址的耗时
+    received_bytes: Family<Counter>,     // 各转发目标从目的地址收到的字节数
+    sent_bytes: Family<Counter>,         // 各转发目标发给目的地址的字节数
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            reconnects: Counter::default(),
+            ping_rtt: Histogram::default(),
+            active: Family::new(&["domain"]),
+            connect_failures: Family::new(&["domain"]),
+            connect_duration: Family::new(&["domain"]),
+            received_bytes: Family::new(&["domain"]),
+            sent_bytes: Family::new(&["domain"]),
+        }
+    }
+
+    // 生成 Prometheus 格式的指标
+    fn render(&self) -> String {
+        let mut e = Encoder::default();
+        e.counter(
+            "http_forward_client_reconnects_total",
+            "Reconnects to the server",
+            &self.reconnects,
+        );
+        e.histogram(
+            "http_forward_client_ping_seconds",
+            "Round trip time of Ping/Pong",
+            &self.ping_rtt,
+        );
+        e.gauge_family(
+            "http_forward_client_active_forwards",
+            "Active forwards",
+            &self.active,
+        );
+        e.counter_family(
+            "http_forward_client_connect_failures_total",
+            "Destination connect failures",
+            &self.connect_failures,
+        );
+        e.histogram_family(
+            "http_forward_client_connect_seconds",
+            "Destination connect latency",
+            &self.connect_duration,
+        );
+        e.counter_family(
+            "http_forward_client_received_bytes_total",
+            "Bytes received from destinations",
+            &self.received_bytes,
+        );
+        e.counter_family(
+            "http_forward_client_sent_bytes_total",
+            "Bytes sent to destinations",
+            &self.sent_bytes,
+        );
+        e.finish()
+    }
+}
+
+// 把服务端 UDP 会话的数据报转发到 destination, 并把回复发回服务端
+async fn handle_udp_session(
+    port: u16,
+    session: u32,
+    destination: String,
+    mut rx: UnboundedReceiver<Vec<u8>>,
+    out_tx: UnboundedSender<Protocol>,
+) -> crate::Result<()> {
+    let addr = lookup_host(&destination)
+        .await
+        .map_err(err!("cannot resolve {}", destination))?
+        .next()
+        .ok_or_else(|| io::Error::from(ErrorKind::AddrNotAvailable))
+        .map_err(err!("cannot resolve {}", destination))?;
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await.map_err(err!())?;
+    socket
+        .connect(addr)
         .await
-        .map_err(err!("{} <=> {}", &req.domain, destination))?;
+        .map_err(err!("cannot connect to {}", destination))?;
+    debug!("udp:{}/{} <=> {}", port, session, destination);
+
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(data) => {
+                    if let Err(e) = socket.send(&data).await {
+                        error!("udp send to {} error: {}", destination, e);
+                    }
+                }
+                None => break,
+            },
+            recv = socket.recv(&mut buf) => match recv {
+                Ok(n) => {
+                    let data = buf[..n].to_vec();
+                    if out_tx.send(Protocol::UdpData { port, session, data }).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => error!("udp recv from {} error: {}", destination, e),
+            },
+        }
+    }
+    debug!("udp:{}/{} closed", port, session);
     Ok(())
 }
 
@@ -163,8 +583,18 @@ fn create_connector(opt: &Opt) -> crate::Result<TlsConnector> {
 
 fn validate_opt() -> Opt {
     let opt: Opt = Opt::from_args();
-    if opt.forward.is_empty() {
-        eprintln!("missing --forward <forward>");
+    if opt.forward.is_empty() && opt.tcp.is_empty() && opt.udp.is_empty() {
+        eprintln!("missing --forward <forward>, --tcp <tcp> or --udp <udp>");
+        exit(1);
+    }
+    for v in &opt.auth {
+        if !opt.forward.iter().any(|f| f.domain == v.domain) {
+            eprintln!("--auth {}: domain is not forwarded", v.domain);
+            exit(1);
+        }
+    }
+    if opt.connect_timeout == 0 || opt.ping_interval == 0 {
+        eprintln!("--connect-timeout and --ping-interval must be greater than 0");
         exit(1);
     }
 
@@ -187,8 +617,8 @@ fn validate_opt() -> Opt {
 // 转发配置
 #[derive(Debug)]
 struct ForwardOption {
-    domain: String,      // 域名
-    destination: String, // 目的地址
+    domain: String,              // 域名
+    destination: UpstreamOption, // 目的地址
 }
 
 #[derive(Debug)]
@@ -207,7 +637,28 @@ impl FromStr for ForwardOption {
         match s.find(':') {
             Some(n) if n < s.len() - 1 => Ok(ForwardOption {
                 domain: s[..n].to_string(),
-                destination: s[n + 1..].to_string(),
+                destination: s[n + 1..].parse().map_err(|_| InvalidForwardOption)?,
+            }),
+            _ => Err(InvalidForwardOption),
+        }
+    }
+}
+
+// TCP/UDP 端口转发配置
+#[derive(Debug)]
+struct PortForwardOption<T> {
+    port: u16,      // 服务端端口
+    destination: T, // 目的地址
+}
+
+impl<T: FromStr> FromStr for PortForwardOption<T> {
+    type Err = InvalidForwardOption;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.find(':') {
+            Some(n) if n < s.len() - 1 => Ok(PortForwardOption {
+                port: s[..n].parse().map_err(|_| InvalidForwardOption)?,
+                destination: s[n + 1..].parse().map_err(|_| InvalidForwardOption)?,
             }),
             _ => Err(InvalidForwardOption),
         }
@@ -0,0 +1,279 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::from_utf8;
+
+use serde_json::Value;
+use structopt::StructOpt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+
+// 命令行参数
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// 服务端管理接口地址，格式为 "ip:端口"
+    #[structopt(short, long, required_unless = "socket")]
+    addr: Option<SocketAddr>,
+
+    /// 服务端本地管理 unix 域 socket 路径，设置后忽略 --addr 和 --token
+    #[structopt(short, long)]
+    socket: Option<PathBuf>,
+
+    /// 管理接口的访问 token
+    #[structopt(short, long, env = "HTTP_FORWARD_ADMIN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// 输出 JSON
+    #[structopt(short, long)]
+    json: bool,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// 列出已注册的客户端
+    Clients,
+    /// 列出已注册和被禁止的域名
+    Domains,
+    /// 断开客户端，参数为客户端 id、地址或者客户端转发的域名
+    Kick { client: String },
+    /// 禁止域名，访问者收到 403，客户端不能注册该域名
+    Block { domain: String },
+    /// 解除禁止域名
+    Unblock { domain: String },
+    /// 排空域名，正在转发的连接不受影响，新连接收到 503，客户端断开后自动取消
+    Drain { domain: String },
+    /// 取消排空域名
+    Undrain { domain: String },
+    /// 重新加载服务端配置
+    Reload,
+}
+
+// 管理接口返回的错误
+#[derive(Debug)]
+struct AdminError(String);
+
+impl Display for AdminError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for AdminError {}
+
+pub async fn run() -> crate::Result<()> {
+    let opt = Opt::from_args();
+    let value = match &opt.command {
+        Command::Clients => request(&opt, "GET", "/clients").await?,
+        Command::Domains => request(&opt, "GET", "/domains").await?,
+        Command::Kick { client } => {
+            let id = find_client(&opt, client).await?;
+            request(&opt, "POST", &format!("/clients/{}/kick", id)).await?
+        }
+        Command::Block { domain } => {
+            request(&opt, "POST", &format!("/domains/{}/block", domain)).await?
+        }
+        Command::Unblock { domain } => {
+            request(&opt, "POST", &format!("/domains/{}/unblock", domain)).await?
+        }
+        Command::Drain { domain } => {
+            request(&opt, "POST", &format!("/domains/{}/drain", domain)).await?
+        }
+        Command::Undrain { domain } => {
+            request(&opt, "POST", &format!("/domains/{}/undrain", domain)).await?
+        }
+        Command::Reload => request(&opt, "POST", "/reload").await?,
+    };
+
+    if opt.json {
+        println!("{:#}", value);
+        return Ok(());
+    }
+    match &opt.command {
+        Command::Clients => print_clients(&value),
+        Command::Domains => print_domains(&value),
+        Command::Kick { .. } => println!("kicked client {}", text(&value["id"])),
+        Command::Block { domain } => println!("blocked {}", domain),
+        Command::Unblock { domain } => println!("unblocked {}", domain),
+        Command::Drain { domain } => println!(
+            "draining {}, {} active connections",
+            domain,
+            text(&value["active"])
+        ),
+        Command::Undrain { domain } => println!("undrained {}", domain),
+        Command::Reload => println!("reloaded"),
+    }
+    Ok(())
+}
+
+// 把客户端 id、地址或域名转换为客户端 id
+async fn find_client(opt: &Opt, client: &str) -> crate::Result<u64> {
+    if let Ok(id) = client.parse() {
+        return Ok(id);
+    }
+    let clients = request(opt, "GET", "/clients").await?;
+    let found = clients.as_array().and_then(|clients| {
+        clients.iter().find(|v| {
+            v["addr"] == client
+                || v["domains"]
+                    .as_array()
+                    .is_some_and(|domains| domains.iter().any(|d| d == client))
+        })
+    });
+    found
+        .and_then(|v| v["id"].as_u64())
+        .ok_or_else(|| AdminError(format!("client {} not found", client)))
+        .map_err(err!())
+}
+
+// 请求管理接口, 返回 JSON 内容
+async fn request(opt: &Opt, method: &str, path: &str) -> crate::Result<Value> {
+    match (&opt.socket, opt.addr) {
+        (Some(socket), _) => {
+            let mut stream = UnixStream::connect(socket)
+                .await
+                .map_err(err!("cannot connect to {}", socket.display()))?;
+            send_request(&mut stream, method, path, None).await
+        }
+        (None, Some(addr)) => {
+            let mut stream = TcpStream::connect(addr)
+                .await
+                .map_err(err!("cannot connect to {}", addr))?;
+            send_request(&mut stream, method, path, opt.token.as_deref()).await
+        }
+        (None, None) => unreachable!(),
+    }
+}
+
+async fn send_request(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+) -> crate::Result<Value> {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nhost: localhost\r\ncontent-length: 0\r\nconnection: close\r\n",
+        method, path
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.map_err(err!())?;
+
+    // 服务端发送响应后关闭连接
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(err!())?;
+    let response = from_utf8(&response).map_err(err!())?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::from(ErrorKind::InvalidData))
+        .map_err(err!("invalid response"))?;
+    let status = head.split(' ').nth(1).unwrap_or_default();
+    let value: Value = serde_json::from_str(body).map_err(err!("invalid response"))?;
+    if !status.starts_with('2') {
+        let msg = value["error"].as_str().unwrap_or(status);
+        return Err(AdminError(msg.to_string())).map_err(err!("{} {}", method, path));
+    }
+    Ok(value)
+}
+
+fn print_clients(value: &Value) {
+    let rows = value.as_array().map_or(&[][..], |v| v.as_slice());
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|v| {
+            let targets: Vec<String> = list(&v["domains"])
+                .into_iter()
+                .chain(list(&v["ports"]).into_iter().map(|p| format!("tcp:{}", p)))
+                .chain(
+                    list(&v["udp_ports"])
+                        .into_iter()
+                        .map(|p| format!("udp:{}", p)),
+                )
+                .collect();
+            vec![
+                text(&v["id"]),
+                text(&v["addr"]),
+                text(&v["subject"]),
+                targets.join(","),
+                text(&v["connected_at"]),
+                text(&v["last_ping"]),
+            ]
+        })
+        .collect();
+    print_table(
+        &[
+            "ID",
+            "ADDR",
+            "SUBJECT",
+            "FORWARDS",
+            "CONNECTED",
+            "LAST PING",
+        ],
+        &rows,
+    );
+}
+
+fn print_domains(value: &Value) {
+    let rows = value.as_array().map_or(&[][..], |v| v.as_slice());
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|v| {
+            let mut state = Vec::new();
+            if v["blocked"] == true {
+                state.push("blocked");
+            }
+            if v["draining"] == true {
+                state.push("draining");
+            }
+            if state.is_empty() {
+                state.push("ok");
+            }
+            vec![
+                text(&v["domain"]),
+                text(&v["client"]),
+                text(&v["addr"]),
+                text(&v["active"]),
+                state.join(","),
+            ]
+        })
+        .collect();
+    print_table(&["DOMAIN", "CLIENT", "ADDR", "ACTIVE", "STATE"], &rows);
+}
+
+fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|v| v.len()).collect();
+    for row in rows {
+        for (w, v) in widths.iter_mut().zip(row) {
+            *w = (*w).max(v.chars().count());
+        }
+    }
+    let header: Vec<String> = header.iter().map(|v| v.to_string()).collect();
+    for row in std::iter::once(&header).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(v, w)| format!("{:<1$}", v, w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+fn list(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map_or(Vec::new(), |v| v.iter().map(text).collect())
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
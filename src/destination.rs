@@ -0,0 +1,765 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::{interval, timeout, Duration};
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use tokio_rustls::TlsConnector;
+use webpki_roots::TLS_SERVER_ROOTS;
+
+use crate::proxy::ProxyVersion;
+use crate::util::{load_certs, load_key};
+use crate::WithContext;
+
+// 到目的地址的连接
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+// 转发目的地址
+#[derive(Debug, Clone)]
+pub enum Destination {
+    Tcp(String),   // 格式为 "地址:端口"
+    Unix(PathBuf), // unix 域 socket 路径
+}
+
+impl Destination {
+    pub async fn connect(&self) -> crate::Result<Box<dyn Stream>> {
+        match self {
+            Destination::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)
+                    .await
+                    .map_err(err!("cannot connect to {}", self))?;
+                Ok(Box::new(stream))
+            }
+            Destination::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .map_err(err!("cannot connect to {}", self))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Destination::Tcp(addr) => Display::fmt(addr, f),
+            Destination::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidDestination;
+
+impl Display for InvalidDestination {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("wrong format", f)
+    }
+}
+
+impl FromStr for Destination {
+    type Err = InvalidDestination;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(InvalidDestination),
+            Some(path) => Ok(Destination::Unix(PathBuf::from(path))),
+            None if s.contains(':') => Ok(Destination::Tcp(s.to_string())),
+            None => Err(InvalidDestination),
+        }
+    }
+}
+
+// 连接目的地址使用的 TLS 配置
+#[derive(Debug, Default)]
+pub struct TlsOption {
+    sni: Option<String>,  // SNI 及证书校验使用的域名，默认为目的地址中的域名
+    ca: Option<String>,   // CA 证书，默认使用内置的根证书
+    insecure: bool,       // 不校验目的地址的证书
+    cert: Option<String>, // 客户端证书
+    key: Option<String>,  // 客户端证书 key
+}
+
+// 健康检查方式
+#[derive(Debug, Clone)]
+pub enum HealthCheck {
+    Tcp,          // 能建立连接即为健康
+    Http(String), // 请求指定路径, 响应状态码为 2xx 或 3xx 即为健康
+}
+
+impl FromStr for HealthCheck {
+    type Err = InvalidDestination;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(HealthCheck::Tcp),
+            "http" => Ok(HealthCheck::Http("/".to_string())),
+            _ => match s.strip_prefix("http:") {
+                Some(path) if path.starts_with('/') => Ok(HealthCheck::Http(path.to_string())),
+                _ => Err(InvalidDestination),
+            },
+        }
+    }
+}
+
+// 转发目标配置，格式为 "目的地址[,目的地址...][?参数&参数...]"
+#[derive(Debug)]
+pub struct UpstreamOption {
+    destinations: Vec<Destination>,
+    tls: Option<TlsOption>,
+    check: Option<HealthCheck>,   // 健康检查方式
+    interval: u64,                // 健康检查间隔（秒）
+    proxy: Option<ProxyVersion>,  // 连接后先发送 PROXY protocol 头
+    connect_timeout: Option<u64>, // 连接超时（秒）
+    idle_timeout: Option<u64>,    // 空闲超时（秒）, 0 表示不限制
+}
+
+impl FromStr for UpstreamOption {
+    type Err = InvalidDestination;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (destinations, params) = match s.split_once('?') {
+            Some((destinations, params)) => (destinations, Some(params)),
+            None => (s, None),
+        };
+        let mut option = UpstreamOption {
+            destinations: destinations
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            tls: None,
+            check: None,
+            interval: 10,
+            proxy: None,
+            connect_timeout: None,
+            idle_timeout: None,
+        };
+
+        for param in params.into_iter().flat_map(|v| v.split('&')) {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("check", Some(v)) => option.check = Some(v.parse()?),
+                ("proxy", Some(v)) => {
+                    option.proxy = Some(v.parse().map_err(|_| InvalidDestination)?)
+                }
+                ("interval", Some(v)) => {
+                    option.interval = v.parse().map_err(|_| InvalidDestination)?;
+                    if option.interval == 0 {
+                        return Err(InvalidDestination);
+                    }
+                }
+                ("connect_timeout", Some(v)) => match v.parse().map_err(|_| InvalidDestination)? {
+                    0 => return Err(InvalidDestination),
+                    v => option.connect_timeout = Some(v),
+                },
+                ("idle_timeout", Some(v)) => {
+                    option.idle_timeout = Some(v.parse().map_err(|_| InvalidDestination)?)
+                }
+                (name, value) => {
+                    let tls = option.tls.get_or_insert_with(TlsOption::default);
+                    match (name, value) {
+                        ("tls", None) => {}
+                        ("insecure", None) => tls.insecure = true,
+                        ("sni", Some(v)) => tls.sni = Some(v),
+                        ("ca", Some(v)) => tls.ca = Some(v),
+                        ("cert", Some(v)) => tls.cert = Some(v),
+                        ("key", Some(v)) => tls.key = Some(v),
+                        _ => return Err(InvalidDestination),
+                    }
+                }
+            }
+        }
+        if let Some(tls) = &option.tls {
+            if tls.cert.is_some() != tls.key.is_some() {
+                return Err(InvalidDestination);
+            }
+        }
+        Ok(option)
+    }
+}
+
+// 转发目标, 包含一个或多个后端, 每次连接轮流选择健康的后端, 连接失败时尝试下一个
+pub struct Upstream {
+    backends: Vec<Backend>,
+    check: Option<HealthCheck>,
+    interval: Duration,
+    proxy: Option<ProxyVersion>,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+    next: AtomicUsize, // 下次连接优先选择的后端
+}
+
+impl Upstream {
+    // connect_timeout 和 idle_timeout 为全局配置, 转发地址的参数优先
+    pub fn new(
+        option: UpstreamOption,
+        connect_timeout: Duration,
+        idle_timeout: Duration,
+    ) -> crate::Result<Self> {
+        let connector = match &option.tls {
+            Some(tls) => Some(create_tls_connector(tls)?),
+            None => None,
+        };
+        let mut backends = Vec::with_capacity(option.destinations.len());
+        for destination in option.destinations {
+            let tls = match (&connector, &option.tls) {
+                (Some(connector), Some(tls)) => {
+                    Some((connector.clone(), tls_server_name(&destination, tls)?))
+                }
+                _ => None,
+            };
+            backends.push(Backend {
+                destination,
+                tls,
+                healthy: AtomicBool::new(true),
+            });
+        }
+        Ok(Self {
+            backends,
+            check: option.check,
+            interval: Duration::from_secs(option.interval),
+            proxy: option.proxy,
+            connect_timeout: option
+                .connect_timeout
+                .map_or(connect_timeout, Duration::from_secs),
+            idle_timeout: option
+                .idle_timeout
+                .map_or(idle_timeout, Duration::from_secs),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // 转发连接的空闲超时, 0 表示不限制
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    // 连接后端, 优先连接健康的后端, 全部失败后再尝试不健康的后端
+    // addrs 为 (访问者地址, 访问者连接的服务端地址), 配置了 proxy 时写入 PROXY protocol 头
+    pub async fn connect(&self, addrs: (SocketAddr, SocketAddr)) -> crate::Result<Box<dyn Stream>> {
+        let header = self.proxy.map(|v| v.header(Some(addrs)));
+        let n = self.backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut last_err = None;
+        for healthy in [true, false] {
+            for i in 0..n {
+                let backend = &self.backends[(start + i) % n];
+                if backend.is_healthy() != healthy {
+                    continue;
+                }
+                let connect = timeout(self.connect_timeout, backend.connect(header.as_deref()));
+                let re = match connect.await {
+                    Ok(re) => re,
+                    Err(e) => Err(e)
+                        .map_err(err!("connect timeout"))
+                        .ctx("destination", &backend.destination),
+                };
+                match re {
+                    Ok(stream) => {
+                        backend.set_healthy(true);
+                        return Ok(stream);
+                    }
+                    Err(e) => {
+                        if n > 1 {
+                            warn!("{}", e);
+                        }
+                        backend.set_healthy(false);
+                        last_err = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    // 定期对所有后端做健康检查, 未配置检查方式时直接返回
+    pub async fn run_health_check(self: Arc<Self>) {
+        let check = match &self.check {
+            Some(check) => check,
+            None => return,
+        };
+        // 健康检查的连接不是代理的, PROXY 头中不带地址
+        let header = self.proxy.map(|v| v.header(None));
+        let mut tick = interval(self.interval);
+        loop {
+            tick.tick().await;
+            for backend in &self.backends {
+                let healthy = matches!(
+                    timeout(self.interval, backend.check(check, header.as_deref())).await,
+                    Ok(Ok(true))
+                );
+                backend.set_healthy(healthy);
+            }
+        }
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, backend) in self.backends.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            Display::fmt(backend, f)?;
+        }
+        Ok(())
+    }
+}
+
+// 后端
+struct Backend {
+    destination: Destination,
+    tls: Option<(TlsConnector, ServerName)>,
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    // header 为 PROXY protocol 头, 在 TLS 握手前发送
+    async fn connect(&self, header: Option<&[u8]>) -> crate::Result<Box<dyn Stream>> {
+        let mut stream = self.destination.connect().await?;
+        if let Some(header) = header {
+            stream
+                .write_all(header)
+                .await
+                .map_err(err!("cannot send proxy header"))
+                .ctx("destination", &self.destination)?;
+        }
+        match &self.tls {
+            Some((connector, server_name)) => {
+                let stream = connector
+                    .connect(server_name.clone(), stream)
+                    .await
+                    .map_err(err!("Tls connect error"))
+                    .ctx("destination", &self.destination)?;
+                Ok(Box::new(stream))
+            }
+            None => Ok(stream),
+        }
+    }
+
+    async fn check(&self, check: &HealthCheck, header: Option<&[u8]>) -> crate::Result<bool> {
+        let mut stream = self.connect(header).await?;
+        let path = match check {
+            HealthCheck::Tcp => return Ok(true),
+            HealthCheck::Http(path) => path,
+        };
+
+        let host = match &self.tls {
+            Some((_, ServerName::DnsName(name))) => name.as_ref().to_string(),
+            _ => match &self.destination {
+                Destination::Tcp(addr) => addr.clone(),
+                Destination::Unix(_) => "localhost".to_string(),
+            },
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        stream.write_all(request.as_bytes()).await.map_err(err!())?;
+
+        // 只读取状态行, 格式为 "HTTP/1.1 200 OK"
+        let mut buf = [0; 32];
+        let mut read = 0;
+        while read < buf.len() {
+            let n = stream.read(&mut buf[read..]).await.map_err(err!())?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        let status = buf[..read]
+            .split(|&v| v == b' ')
+            .nth(1)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse::<u16>().ok());
+        Ok(matches!(status, Some(200..=399)))
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            match healthy {
+                true => info!("{} is healthy", self),
+                false => warn!("{} is unhealthy", self),
+            }
+        }
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.tls {
+            Some(_) => write!(f, "{} (tls)", self.destination),
+            None => Display::fmt(&self.destination, f),
+        }
+    }
+}
+
+fn tls_server_name(destination: &Destination, option: &TlsOption) -> crate::Result<ServerName> {
+    let host = match (&option.sni, destination) {
+        (Some(sni), _) => sni.as_str(),
+        (None, Destination::Tcp(addr)) => match addr.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => addr.as_str(),
+        },
+        (None, Destination::Unix(_)) => "",
+    };
+    // rustls 只支持域名, 不校验证书时目的地址为 IP 也允许连接
+    ServerName::try_from(host)
+        .or_else(|e| match option.insecure {
+            true => ServerName::try_from("localhost"),
+            false => Err(e),
+        })
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "sni required"))
+        .map_err(err!())
+        .ctx("destination", destination)
+}
+
+fn create_tls_connector(option: &TlsOption) -> crate::Result<TlsConnector> {
+    let mut root = RootCertStore::empty();
+    match &option.ca {
+        Some(ca) => {
+            for v in load_certs(ca)? {
+                root.add(&v).map_err(err!())?;
+            }
+        }
+        None => root.add_server_trust_anchors(TLS_SERVER_ROOTS.0.iter().map(|v| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                v.subject,
+                v.spki,
+                v.name_constraints,
+            )
+        })),
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root);
+    let mut config = match (&option.cert, &option.key) {
+        (Some(cert), Some(key)) => builder
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(err!())?,
+        _ => builder.with_no_client_auth(),
+    };
+    if option.insecure {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerifier));
+    }
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// 不校验证书，用于自签名证书
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::path::Path;
+
+    use tokio::net::{TcpListener, UnixListener};
+
+    use super::*;
+
+    #[test]
+    fn parse_destination() {
+        assert!(matches!(
+            "127.0.0.1:8080".parse(),
+            Ok(Destination::Tcp(v)) if v == "127.0.0.1:8080"
+        ));
+        assert!(matches!(
+            "[::1]:8080".parse(),
+            Ok(Destination::Tcp(v)) if v == "[::1]:8080"
+        ));
+        assert!(matches!(
+            "unix:/run/app.sock".parse(),
+            Ok(Destination::Unix(v)) if v == Path::new("/run/app.sock")
+        ));
+        assert!("unix:".parse::<Destination>().is_err());
+        assert!("localhost".parse::<Destination>().is_err());
+        assert_eq!(
+            "unix:/run/app.sock"
+                .parse::<Destination>()
+                .unwrap()
+                .to_string(),
+            "unix:/run/app.sock"
+        );
+    }
+
+    #[test]
+    fn parse_unix_upstream() {
+        let option: UpstreamOption = "unix:/run/a.sock,127.0.0.1:8080".parse().unwrap();
+        assert!(matches!(
+            option.destinations.as_slice(),
+            [Destination::Unix(_), Destination::Tcp(_)]
+        ));
+        assert!("unix:/run/a.sock,".parse::<UpstreamOption>().is_err());
+    }
+
+    #[test]
+    fn parse_tls() {
+        let option: UpstreamOption = "127.0.0.1:8443".parse().unwrap();
+        assert!(option.tls.is_none());
+
+        let option: UpstreamOption = "127.0.0.1:8443?tls".parse().unwrap();
+        let tls = option.tls.unwrap();
+        assert!(tls.sni.is_none() && tls.ca.is_none() && !tls.insecure);
+
+        let s = "127.0.0.1:8443?sni=a.com&ca=ca.pem&cert=c.pem&key=k.pem&insecure";
+        let tls = s.parse::<UpstreamOption>().unwrap().tls.unwrap();
+        assert_eq!(tls.sni.as_deref(), Some("a.com"));
+        assert_eq!(tls.ca.as_deref(), Some("ca.pem"));
+        assert_eq!(tls.cert.as_deref(), Some("c.pem"));
+        assert_eq!(tls.key.as_deref(), Some("k.pem"));
+        assert!(tls.insecure);
+    }
+
+    #[test]
+    fn parse_invalid_tls() {
+        for s in [
+            "127.0.0.1:8443?tls=1",
+            "127.0.0.1:8443?insecure=true",
+            "127.0.0.1:8443?sni",
+            "127.0.0.1:8443?cert=c.pem",
+            "127.0.0.1:8443?key=k.pem",
+            "127.0.0.1:8443?foo=bar",
+        ] {
+            assert!(s.parse::<UpstreamOption>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn server_name() {
+        let name = |destination: &str, option: &str| {
+            let destination = destination.parse().unwrap();
+            let option = format!("127.0.0.1:1?{}", option)
+                .parse::<UpstreamOption>()
+                .unwrap();
+            match tls_server_name(&destination, &option.tls.unwrap()) {
+                Ok(ServerName::DnsName(name)) => Some(name.as_ref().to_string()),
+                _ => None,
+            }
+        };
+        assert_eq!(name("a.com:443", "tls").as_deref(), Some("a.com"));
+        assert_eq!(name("a.com:443", "sni=b.com").as_deref(), Some("b.com"));
+        assert_eq!(
+            name("unix:/run/a.sock", "sni=b.com").as_deref(),
+            Some("b.com")
+        );
+        assert_eq!(name("unix:/run/a.sock", "tls"), None);
+        // rustls 不支持 IP, 需要指定 sni 或不校验证书
+        assert_eq!(name("127.0.0.1:443", "tls"), None);
+        assert_eq!(name("[::1]:443", "tls"), None);
+        assert_eq!(
+            name("127.0.0.1:443", "insecure").as_deref(),
+            Some("localhost")
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        let s = "127.0.0.1:8080,unix:/run/a.sock?check=http:/health&interval=5&proxy=v2\
+                 &connect_timeout=3&idle_timeout=0";
+        let option: UpstreamOption = s.parse().unwrap();
+        assert_eq!(option.destinations.len(), 2);
+        assert!(option.tls.is_none());
+        assert!(matches!(option.check, Some(HealthCheck::Http(v)) if v == "/health"));
+        assert_eq!(option.interval, 5);
+        assert!(matches!(option.proxy, Some(ProxyVersion::V2)));
+        assert_eq!(option.connect_timeout, Some(3));
+        assert_eq!(option.idle_timeout, Some(0));
+
+        let option: UpstreamOption = "127.0.0.1:8080".parse().unwrap();
+        assert!(option.check.is_none());
+        assert_eq!(option.interval, 10);
+        assert!(matches!(
+            "127.0.0.1:8080?check=tcp"
+                .parse::<UpstreamOption>()
+                .unwrap()
+                .check,
+            Some(HealthCheck::Tcp)
+        ));
+        assert!(matches!(
+            "127.0.0.1:8080?check=http".parse::<UpstreamOption>().unwrap().check,
+            Some(HealthCheck::Http(v)) if v == "/"
+        ));
+    }
+
+    #[test]
+    fn parse_invalid_options() {
+        for s in [
+            "127.0.0.1:8080?check=udp",
+            "127.0.0.1:8080?check=http:health",
+            "127.0.0.1:8080?check",
+            "127.0.0.1:8080?interval=0",
+            "127.0.0.1:8080?interval=x",
+            "127.0.0.1:8080?proxy=v3",
+            "127.0.0.1:8080?connect_timeout=0",
+            "127.0.0.1:8080?idle_timeout=-1",
+        ] {
+            assert!(s.parse::<UpstreamOption>().is_err(), "{}", s);
+        }
+    }
+
+    // 监听本地端口, 每个连接发送 id 后关闭
+    async fn listen(id: u8) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(&[id]).await;
+            }
+        });
+        addr
+    }
+
+    // 已关闭的本地端口
+    async fn closed() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn upstream(destinations: &[&str]) -> Upstream {
+        let option = destinations.join(",").parse().unwrap();
+        Upstream::new(option, Duration::from_secs(1), Duration::ZERO).unwrap()
+    }
+
+    // 连接 upstream, 返回所连后端的 id
+    async fn connect(upstream: &Upstream) -> crate::Result<u8> {
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let mut stream = upstream.connect((addr, addr)).await?;
+        let mut id = [0];
+        stream.read_exact(&mut id).await.map_err(err!())?;
+        Ok(id[0])
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        let upstream = upstream(&[&listen(1).await, &listen(2).await]);
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            ids.push(connect(&upstream).await.unwrap());
+        }
+        assert_eq!(ids, [1, 2, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn skip_unhealthy() {
+        let upstream = upstream(&[&listen(1).await, &listen(2).await]);
+        upstream.backends[0].set_healthy(false);
+        for _ in 0..4 {
+            assert_eq!(connect(&upstream).await.unwrap(), 2);
+        }
+        assert!(!upstream.backends[0].is_healthy());
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let upstream = upstream(&[&closed().await, &listen(2).await]);
+        assert_eq!(connect(&upstream).await.unwrap(), 2);
+        assert!(!upstream.backends[0].is_healthy());
+        // 之后不再尝试不健康的后端
+        assert_eq!(connect(&upstream).await.unwrap(), 2);
+        assert_eq!(connect(&upstream).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn all_unhealthy() {
+        let upstream = upstream(&[&closed().await, &listen(2).await]);
+        upstream.backends[0].set_healthy(false);
+        upstream.backends[1].set_healthy(false);
+        assert_eq!(connect(&upstream).await.unwrap(), 2);
+        assert!(upstream.backends[1].is_healthy());
+
+        let upstream = self::upstream(&[&closed().await]);
+        assert!(connect(&upstream).await.is_err());
+    }
+
+    // 监听本地端口, 对每个连接应答状态码 status
+    async fn http(status: u16) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn health_check() {
+        let http_check = HealthCheck::Http("/".to_string());
+        let upstream = upstream(&[&http(200).await, &http(302).await, &http(503).await]);
+        let mut checks = Vec::new();
+        for backend in &upstream.backends {
+            checks.push(backend.check(&http_check, None).await.unwrap());
+        }
+        assert_eq!(checks, [true, true, false]);
+
+        let upstream = self::upstream(&[&closed().await]);
+        assert!(upstream.backends[0]
+            .check(&HealthCheck::Tcp, None)
+            .await
+            .is_err());
+        let upstream = self::upstream(&[&listen(1).await]);
+        assert!(upstream.backends[0]
+            .check(&HealthCheck::Tcp, None)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn connect_unix() {
+        let path = std::env::temp_dir().join(format!("destination_{}.sock", std::process::id()));
+        let _ = remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let destination = Destination::Unix(path.clone());
+        let (connect, accept) = tokio::join!(destination.connect(), listener.accept());
+        let mut stream = connect.unwrap();
+        let (mut peer, _) = accept.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        peer.read_exact(&mut buf).await.unwrap();
+        remove_file(&path).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use crate::http::Status;
+
+// 错误页面格式
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Format::Html => Format::Json,
+            Format::Json => Format::Html,
+        }
+    }
+}
+
+// 发给访问者的错误页面, 从目录读取, 文件名为 "状态码.html" 或 "状态码.json",
+// 离线页面为 "offline.html" 或 "offline.json", 子目录名为域名, 其中的页面只用于该域名,
+// 优先于目录下的页面. 忽略其他文件.
+//
+// 页面中的 {domain}、{request_id}、{status}、{reason} 替换为域名、请求 ID、状态码和原因短语,
+// 域名按页面格式转义
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    pages: HashMap<(Option<String>, String, Format), String>, // key 为 (域名, 页面名称, 格式)
+}
+
+impl ErrorPages {
+    pub fn load(dir: &str) -> crate::Result<Self> {
+        let mut pages = ErrorPages::default();
+        for entry in read_dir(dir).map_err(err!("cannot open {}", dir))? {
+            let path = entry.map_err(err!("cannot open {}", dir))?.path();
+            if path.is_dir() {
+                let domain = match path.file_name().and_then(|v| v.to_str()) {
+                    Some(domain) => domain.to_string(),
+                    None => continue,
+                };
+                let dir = path.to_string_lossy();
+                for entry in read_dir(&path).map_err(err!("cannot open {}", dir))? {
+                    let path = entry.map_err(err!("cannot open {}", dir))?.path();
+                    pages.insert(Some(domain.clone()), &path)?;
+                }
+            } else {
+                pages.insert(None, &path)?;
+            }
+        }
+        Ok(pages)
+    }
+
+    fn insert(&mut self, domain: Option<String>, path: &Path) -> crate::Result<()> {
+        let format = match path.extension().and_then(|v| v.to_str()) {
+            Some("html") => Format::Html,
+            Some("json") => Format::Json,
+            _ => return Ok(()),
+        };
+        let name = match path.file_stem().and_then(|v| v.to_str()) {
+            Some(name) if name == "offline" || name.parse::<u16>().is_ok() => name.to_string(),
+            _ => return Ok(()),
+        };
+        let content =
+            read_to_string(path).map_err(err!("cannot open {}", path.to_string_lossy()))?;
+        self.pages.insert((domain, name, format), content);
+        Ok(())
+    }
+
+    // 生成错误页面, 返回 (Content-Type, 内容), 没有对应的页面时返回 None.
+    // page 为优先使用的页面名称, 没有时使用状态码对应的页面.
+    // 按 Accept 头选择格式, 域名的页面优先, 其次是同一页面的其他格式
+    pub fn render(
+        &self,
+        page: Option<&str>,
+        status: Status,
+        domain: &str,
+        request_id: &str,
+        accept: Option<&str>,
+    ) -> Option<(&'static str, String)> {
+        if self.pages.is_empty() {
+            return None;
+        }
+        let format = if prefers_json(accept) {
+            Format::Json
+        } else {
+            Format::Html
+        };
+        let code = status.code();
+        let status_page = code.to_string();
+        let mut keys = Vec::new();
+        for name in page.iter().copied().chain([status_page.as_str()]) {
+            for d in [Some(domain), None] {
+                for f in [format, format.other()] {
+                    keys.push((d.map(|v| v.to_string()), name.to_string(), f));
+                }
+            }
+        }
+        let (format, page) = keys
+            .into_iter()
+            .find_map(|key| self.pages.get(&key).map(|page| (key.2, page)))?;
+
+        let domain = match format {
+            Format::Html => escape_html(domain),
+            Format::Json => escape_json(domain),
+        };
+        let body = page
+            .replace("{domain}", &domain)
+            .replace("{request_id}", request_id)
+            .replace("{status}", &code.to_string())
+            .replace("{reason}", status.reason_phrase());
+        Some((format.content_type(), body))
+    }
+}
+
+// Accept 头中 JSON 的权重高于 HTML 时使用 JSON, 不考虑通配符
+fn prefers_json(accept: Option<&str>) -> bool {
+    let mut html = 0.0;
+    let mut json = 0.0;
+    for item in accept.unwrap_or_default().split(',') {
+        let mut params = item.split(';');
+        let media = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let q = params
+            .filter_map(|v| v.trim().strip_prefix("q="))
+            .find_map(|v| v.parse::<f64>().ok())
+            .unwrap_or(1.0);
+        if media == "text/html" || media == "application/xhtml+xml" {
+            html = q.max(html);
+        } else if media == "application/json" || media.ends_with("+json") {
+            json = q.max(json);
+        }
+    }
+    json > html
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// 转义为 JSON 字符串的内容, 不包括两边的引号
+fn escape_json(s: &str) -> String {
+    let quoted = serde_json::Value::from(s).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+    use crate::http::{BAD_GATEWAY, NOT_FOUND};
+
+    fn pages(list: &[(Option<&str>, &str, Format, &str)]) -> ErrorPages {
+        let mut pages = ErrorPages::default();
+        for &(domain, name, format, content) in list {
+            let key = (domain.map(|v| v.to_string()), name.to_string(), format);
+            pages.pages.insert(key, content.to_string());
+        }
+        pages
+    }
+
+    #[test]
+    fn accept() {
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("text/html")));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("application/problem+json")));
+        assert!(prefers_json(Some("text/html;q=0.5, application/json")));
+        assert!(!prefers_json(Some("text/html, application/json;q=0.9")));
+        assert!(!prefers_json(Some("application/json, text/html")));
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(
+            escape_html("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape_json("a\"b\\c\n"), "a\\\"b\\\\c\\n");
+    }
+
+    #[test]
+    fn substitute() {
+        let pages = pages(&[
+            (
+                None,
+                "502",
+                Format::Html,
+                "<p>{domain} {request_id} {status} {reason}</p>",
+            ),
+            (
+                None,
+                "502",
+                Format::Json,
+                r#"{"domain":"{domain}","id":"{request_id}"}"#,
+            ),
+        ]);
+        let domain = "<a\"b>";
+        let html = pages.render(None, BAD_GATEWAY, domain, "0123", Some("text/html"));
+        assert_eq!(
+            html,
+            Some((
+                "text/html; charset=utf-8",
+                "<p>&lt;a&quot;b&gt; 0123 502 Bad Gateway</p>".to_string()
+            ))
+        );
+        let json = pages.render(None, BAD_GATEWAY, domain, "0123", Some("application/json"));
+        assert_eq!(
+            json,
+            Some((
+                "application/json",
+                r#"{"domain":"<a\"b>","id":"0123"}"#.to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn select_page() {
+        let pages = pages(&[
+            (None, "404", Format::Html, "global"),
+            (Some("a.com"), "404", Format::Html, "a.com"),
+            (None, "offline", Format::Json, "offline"),
+        ]);
+        let render = |page, domain, accept| {
+            pages
+                .render(page, NOT_FOUND, domain, "0123", accept)
+                .map(|(_, body)| body)
+        };
+        assert_eq!(render(None, "a.com", None).as_deref(), Some("a.com"));
+        assert_eq!(render(None, "b.com", None).as_deref(), Some("global"));
+        // 没有 JSON 页面时使用 HTML 页面
+        assert_eq!(
+            render(None, "b.com", Some("application/json")).as_deref(),
+            Some("global")
+        );
+        assert_eq!(
+            render(Some("offline"), "a.com", None).as_deref(),
+            Some("offline")
+        );
+        assert_eq!(pages.render(None, BAD_GATEWAY, "a.com", "0123", None), None);
+        assert_eq!(
+            ErrorPages::default().render(None, NOT_FOUND, "a.com", "0123", None),
+            None
+        );
+    }
+
+    #[test]
+    fn load() {
+        let dir = std::env::temp_dir().join(format!("error_pages_{}", std::process::id()));
+        create_dir_all(dir.join("a.com")).unwrap();
+        write(dir.join("404.html"), "global").unwrap();
+        write(dir.join("offline.json"), "offline").unwrap();
+        write(dir.join("a.com/404.html"), "a.com").unwrap();
+        write(dir.join("readme.txt"), "ignored").unwrap();
+        write(dir.join("foo.html"), "ignored").unwrap();
+        let pages = ErrorPages::load(&dir.to_string_lossy());
+        remove_dir_all(&dir).unwrap();
+
+        let mut keys: Vec<_> = pages.unwrap().pages.into_keys().collect();
+        keys.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        assert_eq!(
+            keys,
+            [
+                (None, "404".to_string(), Format::Html),
+                (None, "offline".to_string(), Format::Json),
+                (Some("a.com".to_string()), "404".to_string(), Format::Html),
+            ]
+        );
+    }
+}
@@ -1,9 +1,13 @@
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::str::from_utf8;
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
 
 const BUF_SIZE: usize = 1024;
 
@@ -23,25 +27,95 @@ impl Status {
         }
     }
 
-    pub async fn send(self, stream: &mut (impl AsyncWrite + Unpin)) -> crate::Result<()> {
-        let response = format!(
-            "HTTP/1.1 {} {}\r\ncontent-length: 0\r\n\r\n",
-            self.code, self.reason_phrase
-        );
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn reason_phrase(&self) -> &'static str {
+        self.reason_phrase
+    }
+
+    // 发送带额外响应头的空响应
+    pub async fn send_with_headers(
+        self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        headers: &[(&str, &str)],
+    ) -> crate::Result<()> {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.code, self.reason_phrase);
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("content-length: 0\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.map_err(err!())
+    }
+
+    // 发送带 JSON 内容的响应
+    pub async fn send_json(
+        self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        body: &str,
+    ) -> crate::Result<()> {
+        self.send_body(stream, "application/json", body).await
+    }
+
+    async fn send_body(
+        self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        content_type: &str,
+        body: &str,
+    ) -> crate::Result<()> {
+        self.send_body_with_headers(stream, &[], content_type, body)
+            .await
+    }
+
+    // 发送带额外响应头和内容的响应
+    pub async fn send_body_with_headers(
+        self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        headers: &[(&str, &str)],
+        content_type: &str,
+        body: &str,
+    ) -> crate::Result<()> {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.code, self.reason_phrase);
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!(
+            "content-type: {}\r\ncontent-length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        ));
         stream.write_all(response.as_bytes()).await.map_err(err!())
     }
 }
 
+pub const OK: Status = Status::new(200, "OK");
+
+pub const FOUND: Status = Status::new(302, "Found");
+
+pub const BAD_REQUEST: Status = Status::new(400, "Bad Request");
+
+pub const UNAUTHORIZED: Status = Status::new(401, "Unauthorized");
+
+pub const FORBIDDEN: Status = Status::new(403, "Forbidden");
+
+pub const NOT_FOUND: Status = Status::new(404, "Not Found");
+
+pub const TOO_MANY_REQUESTS: Status = Status::new(429, "Too Many Requests");
+
 pub const BAD_GATEWAY: Status = Status::new(502, "Bad Gateway");
 
+pub const SERVICE_UNAVAILABLE: Status = Status::new(503, "Service Unavailable");
+
 pub const GATEWAY_TIMEOUT: Status = Status::new(504, "Gateway Timeout");
 
 #[derive(Debug)]
-struct HeaderTooLarge;
+struct HeaderTooLarge(usize);
 
 impl Display for HeaderTooLarge {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "header size exceeds {} bytes", MAX_BUF_SIZE)
+        write!(f, "header size exceeds {} bytes", self.0)
     }
 }
 
@@ -52,83 +126,884 @@ pub struct ParseResult {
     pub domain: String, // 域名
 }
 
-enum State {
-    Start,
-    ParseHeader(usize),
+impl ParseResult {
+    // 请求行中的方法、路径和协议版本
+    pub fn request_line(&self) -> Option<(&str, &str, &str)> {
+        let line = self.lines().next()?;
+        let mut parts = line.split(' ');
+        Some((parts.next()?, parts.next()?, parts.next()?))
+    }
+
+    // 已读取数据中的请求头, 名称不区分大小写
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    }
+
+    // 完整的行, 不包括最后一个未读完的行
+    fn lines(&self) -> impl Iterator<Item = &str> {
+        let end = self.buf.windows(2).rposition(|v| v == b"\r\n").unwrap_or(0);
+        self.buf[..end]
+            .split(|&v| v == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .map_while(|line| from_utf8(line).ok())
+    }
+}
+
+// 读取完整的请求头, 域名为 Host 头去掉端口后的值, 没有 Host 头时为空
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> crate::Result<ParseResult> {
+    read_head(stream, MAX_BUF_SIZE).await
 }
 
-// 从 Host 头解析域名
+// 读取访问者的第一个请求头, 从 Host 头解析域名, 其他头用于访问控制和访问日志
 pub async fn parse_domain(stream: &mut (impl AsyncRead + Unpin)) -> crate::Result<ParseResult> {
-    let mut buf = Vec::with_capacity(BUF_SIZE);
-    unsafe { buf.set_len(buf.capacity()) };
+    read_head(stream, MAX_HEAD_SIZE).await
+}
 
+async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_size: usize,
+) -> crate::Result<ParseResult> {
+    let mut buf = vec![0; BUF_SIZE];
     let mut read = 0;
-    let mut state = State::Start;
-    loop {
+    while !buf[..read].windows(4).any(|v| v == b"\r\n\r\n") {
+        if read == buf.len() {
+            if read < max_size {
+                buf.resize((read * 2).min(max_size), 0);
+            } else {
+                return Err(HeaderTooLarge(max_size)).map_err(err!());
+            }
+        }
         let n = stream.read(&mut buf[read..]).await.map_err(err!())?;
         if n == 0 {
             return Err(io::Error::from(ErrorKind::UnexpectedEof)).map_err(err!());
         }
         read += n;
+    }
+    buf.truncate(read);
+    let mut result = ParseResult {
+        buf,
+        domain: String::new(),
+    };
+    if let Some(host) = result.header("host") {
+        result.domain = strip_port(host).to_string();
+    }
+    Ok(result)
+}
 
-        'a: loop {
-            match state {
-                State::Start => match find_r(0, read, &buf) {
-                    Some(pos) => state = State::ParseHeader(pos + 2),
-                    None => break,
-                },
-                State::ParseHeader(mut start) => loop {
-                    match find_r(start, read, &buf) {
-                        Some(end) => match extract_domain(&buf[start..end]) {
-                            Some(domain) => {
-                                let domain = from_utf8(domain).map_err(err!())?.trim().to_string();
-                                buf.truncate(read);
-                                return Ok(ParseResult { domain, buf });
-                            }
-                            None => start = end + 2,
-                        },
-                        None => {
-                            state = State::ParseHeader(start);
-                            break 'a;
-                        }
-                    }
-                },
+// 去掉 Host 头中的端口, 如 "a.com:8443" 和 "[::1]:8443", IPv6 地址同时去掉方括号
+fn strip_port(host: &str) -> &str {
+    let (name, port) = match host.strip_prefix('[').and_then(|v| v.split_once(']')) {
+        Some((addr, "")) => return addr,
+        Some((addr, rest)) => match rest.strip_prefix(':') {
+            Some(port) => (addr, port),
+            None => return host,
+        },
+        None => match host.rsplit_once(':') {
+            Some((name, port)) if !name.contains(':') => (name, port),
+            _ => return host,
+        },
+    };
+    if !name.is_empty() && port.bytes().all(|v| v.is_ascii_digit()) {
+        name
+    } else {
+        host
+    }
+}
+
+// 请求头的最大长度
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// 解析后的请求头
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    // 解析以空行结尾的请求头
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let s = from_utf8(buf).ok()?;
+        let mut lines = s.split("\r\n");
+        let mut parts = lines.next()?.split(' ');
+        let method = parts.next()?.to_string();
+        let path = parts.next()?.to_string();
+        let version = parts.next()?.to_string();
+        if parts.next().is_some() {
+            return None;
+        }
+        let headers = parse_headers(lines)?;
+        Some(Self {
+            method,
+            path,
+            version,
+            headers,
+        })
+    }
+
+    // 名称不区分大小写
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    // 所有名称为 name 的头中以逗号分隔的值
+    fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
+
+    // 请求体的长度, 见 RFC 7230 3.3.3. 同时有 Transfer-Encoding 和 Content-Length、
+    // 多个 Content-Length 不一致或者最后的传输编码不是 chunked 时返回 None,
+    // 这些请求在后端可能被按不同的边界解析
+    fn body(&self) -> Option<Body> {
+        let lengths: Vec<_> = self.values("content-length").collect();
+        if self.header("transfer-encoding").is_some() {
+            let codings: Vec<_> = self.values("transfer-encoding").collect();
+            let (last, rest) = codings.split_last()?;
+            let chunked = |v: &&str| v.eq_ignore_ascii_case("chunked");
+            if !lengths.is_empty() || !chunked(last) || rest.iter().any(chunked) {
+                return None;
             }
+            return Some(Body::Chunked);
+        }
+        match lengths.split_first() {
+            None if self.header("content-length").is_some() => None,
+            None => Some(Body::Empty),
+            Some((len, rest)) => {
+                if rest.iter().any(|v| v != len) || !len.bytes().all(|v| v.is_ascii_digit()) {
+                    return None;
+                }
+                len.parse().ok().map(Body::Length)
+            }
+        }
+    }
+
+    // 删除所有名称为 name 的头
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn append(&mut self, name: &str, value: String) {
+        self.headers.push((name.to_string(), value));
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut s = format!("{} {} {}\r\n", self.method, self.path, self.version);
+        for (k, v) in &self.headers {
+            s.push_str(k);
+            s.push_str(": ");
+            s.push_str(v);
+            s.push_str("\r\n");
         }
+        s.push_str("\r\n");
+        s.into_bytes()
+    }
+
+    // 请求后连接是否转为其他协议, 之后的数据不再按 HTTP 解析
+    fn is_upgrade(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+            || (self.header("upgrade").is_some()
+                && self.header("connection").is_some_and(|v| {
+                    v.split(',')
+                        .any(|v| v.trim().eq_ignore_ascii_case("upgrade"))
+                }))
+    }
+}
 
-        if read == buf.capacity() {
-            if read < MAX_BUF_SIZE {
-                buf.reserve(BUF_SIZE);
-                unsafe { buf.set_len(buf.capacity()) };
+// 解析后的响应头, 只用于确定响应体的长度
+struct ResponseHead {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    // 解析以空行结尾的响应头
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let s = from_utf8(buf).ok()?;
+        let mut lines = s.split("\r\n");
+        let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+        let headers = parse_headers(lines)?;
+        Some(Self { status, headers })
+    }
+
+    // 响应体的长度, method 为对应请求的方法, 见 RFC 7230 3.3.3
+    fn body(&self, method: &str) -> Body {
+        if method.eq_ignore_ascii_case("HEAD")
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            return Body::Empty;
+        }
+        if let Some(codings) = find_header(&self.headers, "transfer-encoding") {
+            let last = codings.rsplit(',').next().unwrap_or_default();
+            return if last.trim().eq_ignore_ascii_case("chunked") {
+                Body::Chunked
             } else {
-                return Err(HeaderTooLarge).map_err(err!());
+                Body::UntilClose
+            };
+        }
+        match find_header(&self.headers, "content-length") {
+            Some(len) => len.parse().map_or(Body::UntilClose, Body::Length),
+            None => Body::UntilClose,
+        }
+    }
+}
+
+// 解析请求头或响应头中起始行之后的头, 到空行为止.
+// 名称为空或含有空白 (包括折行), 或者值中含有单独的 CR、LF 时返回 None
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Option<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        if name.is_empty()
+            || name.bytes().any(|v| v.is_ascii_whitespace())
+            || value.bytes().any(|v| v == b'\r' || v == b'\n')
+        {
+            return None;
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Some(headers)
+}
+
+// 名称不区分大小写
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+// 消息体的长度
+enum Body {
+    Empty,
+    Length(u64),
+    Chunked,
+    UntilClose, // 到连接关闭为止, 只用于响应
+}
+
+#[derive(Debug)]
+struct InvalidRequest;
+
+impl Display for InvalidRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("invalid request", f)
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
+
+#[derive(Debug)]
+struct InvalidResponse;
+
+impl Display for InvalidResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("invalid response", f)
+    }
+}
+
+impl std::error::Error for InvalidResponse {}
+
+// 带缓冲的读取
+struct Input<'a, R> {
+    reader: &'a mut R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Input<'_, R> {
+    // 读取更多数据到缓冲区, 连接关闭时返回 false
+    async fn fill(&mut self) -> crate::Result<bool> {
+        let mut buf = [0; 8 * 1024];
+        let n = self.reader.read(&mut buf).await.map_err(err!())?;
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(n > 0)
+    }
+
+    // 读取直到 pattern, 返回包含 pattern 的长度, 连接在读到 pattern 前关闭时返回 None
+    async fn read_until(&mut self, pattern: &[u8], max: usize) -> crate::Result<Option<usize>> {
+        let mut start = 0;
+        loop {
+            if let Some(pos) = self.buf[start..]
+                .windows(pattern.len())
+                .position(|v| v == pattern)
+            {
+                return Ok(Some(start + pos + pattern.len()));
+            }
+            if self.buf.len() > max {
+                return Err(HeaderTooLarge(max)).map_err(err!());
+            }
+            start = self.buf.len().saturating_sub(pattern.len() - 1);
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // 把 n 字节原样写入 writer
+    async fn copy(&mut self, n: u64, writer: &mut (impl AsyncWrite + Unpin)) -> crate::Result<()> {
+        let mut remaining = n;
+        while remaining > 0 {
+            if self.buf.is_empty() && !self.fill().await? {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof)).map_err(err!());
+            }
+            let len = self.buf.len().min(remaining as usize);
+            writer.write_all(&self.buf[..len]).await.map_err(err!())?;
+            self.buf.drain(..len);
+            remaining -= len as u64;
+        }
+        Ok(())
+    }
+
+    // 把缓冲区中的数据和之后读取的所有数据原样写入 writer
+    async fn copy_rest(&mut self, writer: &mut (impl AsyncWrite + Unpin)) -> crate::Result<()> {
+        writer.write_all(&self.buf).await.map_err(err!())?;
+        self.buf.clear();
+        tokio::io::copy(self.reader, writer).await.map_err(err!())?;
+        Ok(())
+    }
+
+    // 按 chunked 编码复制消息体, 包括结尾的 trailer. 分块长度格式错误时返回 false
+    async fn copy_chunked(
+        &mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> crate::Result<bool> {
+        loop {
+            let end = self
+                .read_until(b"\r\n", MAX_HEAD_SIZE)
+                .await?
+                .ok_or(InvalidRequest)
+                .map_err(err!())?;
+            // 长度格式错误或加上结尾的 "\r\n" 后超过 u64 时不写入 writer
+            let size = match chunk_size(&self.buf[..end - 2]) {
+                Some(size) => size,
+                None => return Ok(false),
+            };
+            let len = match size.checked_add(2) {
+                Some(len) => len,
+                None => return Ok(false),
+            };
+            self.copy(end as u64, writer).await?;
+            if size == 0 {
+                break;
+            }
+            self.copy(len, writer).await?;
+        }
+        // trailer, 以空行结束
+        loop {
+            let end = self
+                .read_until(b"\r\n", MAX_HEAD_SIZE)
+                .await?
+                .ok_or(InvalidRequest)
+                .map_err(err!())?;
+            self.copy(end as u64, writer).await?;
+            if end == 2 {
+                return Ok(true);
             }
         }
     }
 }
 
-fn find_r(start: usize, end: usize, s: &[u8]) -> Option<usize> {
-    for i in start..end {
-        if s[i] == b'\r' {
-            return Some(i);
+// 解析分块长度行, 忽略扩展
+fn chunk_size(line: &[u8]) -> Option<u64> {
+    let line = from_utf8(line).ok()?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || !size.bytes().all(|v| v.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
+}
+
+// 转发访问者与后端之间的 HTTP/1.1 连接, 访问者发来的每个请求头经过 rewrite 修改.
+// buf 为已经从访问者读取的数据. 后端对协议升级请求响应 101 (CONNECT 为 2xx) 后不再解析,
+// 原样复制剩余数据
+pub async fn copy_http(
+    visitor: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    backend: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buf: Vec<u8>,
+    rewrite: impl FnMut(&mut RequestHead),
+) -> crate::Result<()> {
+    let (mut reader, mut writer) = split(visitor);
+    let (mut backend_reader, mut backend_writer) = split(backend);
+    let (tx, rx) = unbounded_channel();
+    let request = async {
+        copy_requests(&mut reader, &mut backend_writer, buf, tx, rewrite).await?;
+        backend_writer.shutdown().await.map_err(err!())
+    };
+    let response = async {
+        copy_responses(&mut backend_reader, &mut writer, rx).await?;
+        writer.shutdown().await.map_err(err!())
+    };
+    tokio::try_join!(request, response)?;
+    Ok(())
+}
+
+// 请求侧发给响应侧的消息
+enum Exchange {
+    Request(Pending), // 已发给后端、等待响应的请求
+    BadRequest,       // 请求格式错误, 之前的请求都响应后返回 400 并关闭连接
+}
+
+// 已发给后端、等待响应的请求
+struct Pending {
+    method: String,
+    upgraded: Option<oneshot::Sender<bool>>, // 协议升级请求, 用于通知请求侧是否升级成功
+}
+
+// 把 reader 上的请求逐个复制到 writer, 每个请求头经过 rewrite 修改, 请求体原样复制.
+// 协议升级请求等待后端的响应, 升级成功后原样复制剩余数据, 否则继续按 HTTP 解析.
+// 格式错误或请求体长度有歧义的请求不发给后端, 由响应侧返回 400
+async fn copy_requests(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    buf: Vec<u8>,
+    tx: UnboundedSender<Exchange>,
+    mut rewrite: impl FnMut(&mut RequestHead),
+) -> crate::Result<()> {
+    let mut input = Input { reader, buf };
+    loop {
+        let end = match input.read_until(b"\r\n\r\n", MAX_HEAD_SIZE).await? {
+            Some(end) => end,
+            None if input.buf.is_empty() => return Ok(()),
+            None => return Err(InvalidRequest).map_err(err!()),
+        };
+        let parsed = RequestHead::parse(&input.buf[..end])
+            .and_then(|head| head.body().map(|body| (head, body)));
+        let (mut head, body) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                let _ = tx.send(Exchange::BadRequest);
+                return Ok(());
+            }
+        };
+        input.buf.drain(..end);
+        if let Body::Length(len) = body {
+            // 相同的多个 Content-Length 合并为一个, 后端不一定支持
+            if head.values("content-length").nth(1).is_some() {
+                head.remove("content-length");
+                head.append("Content-Length", len.to_string());
+            }
+        }
+        rewrite(&mut head);
+
+        // 先通知响应侧再发送请求, 响应侧收到响应时总能找到对应的请求
+        let (upgraded, result) = if head.is_upgrade() {
+            let (tx, rx) = oneshot::channel();
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+        let pending = Pending {
+            method: head.method.clone(),
+            upgraded,
+        };
+        if tx.send(Exchange::Request(pending)).is_err() {
+            // 响应侧已经结束
+            return Ok(());
+        }
+        writer.write_all(&head.to_bytes()).await.map_err(err!())?;
+
+        match body {
+            Body::Length(len) => input.copy(len, writer).await?,
+            Body::Chunked => {
+                if !input.copy_chunked(writer).await? {
+                    let _ = tx.send(Exchange::BadRequest);
+                    return Ok(());
+                }
+            }
+            Body::Empty | Body::UntilClose => {}
+        }
+
+        if let Some(result) = result {
+            match result.await {
+                Ok(true) => return input.copy_rest(writer).await,
+                Ok(false) => {}
+                Err(_) => return Ok(()),
+            }
         }
     }
-    None
 }
 
-fn extract_domain(s: &[u8]) -> Option<&[u8]> {
-    let mut s = s.split(|&v| v == b':');
-    if eq_host(s.next()?) {
-        s.next()
-    } else {
-        None
+// 把 reader 上的响应逐个复制到 writer, 按对应请求的方法和响应头确定响应体的长度.
+// 协议升级成功、后端发来没有对应请求的数据或响应体到连接关闭为止时, 原样复制剩余数据
+async fn copy_responses(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    mut rx: UnboundedReceiver<Exchange>,
+) -> crate::Result<()> {
+    let mut input = Input {
+        reader,
+        buf: Vec::new(),
+    };
+    loop {
+        let exchange = loop {
+            match rx.try_recv() {
+                Ok(exchange) => break Some(exchange),
+                Err(TryRecvError::Empty) if input.buf.is_empty() => {
+                    tokio::select! {
+                        exchange = rx.recv() => break exchange,
+                        more = input.fill() => {
+                            if !more? {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+                // 后端主动发来的数据, 或者请求侧已经结束
+                Err(_) => break None,
+            }
+        };
+        let mut pending = match exchange {
+            Some(Exchange::Request(pending)) => pending,
+            Some(Exchange::BadRequest) => {
+                let headers = [("connection", "close")];
+                return BAD_REQUEST.send_with_headers(writer, &headers).await;
+            }
+            None => return input.copy_rest(writer).await,
+        };
+
+        // 1xx 临时响应之后还有最终响应
+        let head = loop {
+            let end = match input.read_until(b"\r\n\r\n", MAX_HEAD_SIZE).await? {
+                Some(end) => end,
+                // 请求体格式错误时后端可能不响应就关闭连接
+                None => match rx.try_recv() {
+                    Ok(Exchange::BadRequest) => {
+                        let headers = [("connection", "close")];
+                        return BAD_REQUEST.send_with_headers(writer, &headers).await;
+                    }
+                    _ => return input.copy_rest(writer).await,
+                },
+            };
+            let head = ResponseHead::parse(&input.buf[..end])
+                .ok_or(InvalidResponse)
+                .map_err(err!())?;
+            input.copy(end as u64, writer).await?;
+            if head.status == 101 || !(100..200).contains(&head.status) {
+                break head;
+            }
+        };
+
+        let connect = pending.method.eq_ignore_ascii_case("CONNECT");
+        let upgraded = head.status == 101 || (connect && (200..300).contains(&head.status));
+        if let Some(tx) = pending.upgraded.take() {
+            let _ = tx.send(upgraded);
+        }
+        if upgraded {
+            return input.copy_rest(writer).await;
+        }
+        match head.body(&pending.method) {
+            Body::Empty => {}
+            Body::Length(len) => input.copy(len, writer).await?,
+            Body::Chunked => {
+                if !input.copy_chunked(writer).await? {
+                    return Err(InvalidResponse).map_err(err!());
+                }
+            }
+            Body::UntilClose => return input.copy_rest(writer).await,
+        }
+    }
+}
+
+// 去掉请求中访问者伪造的 X-Forwarded-* 和 Forwarded 头, 添加由服务端确定的值
+pub fn set_forwarded(head: &mut RequestHead, visitor: IpAddr, proto: &str) {
+    for name in [
+        "x-forwarded-for",
+        "x-forwarded-proto",
+        "x-forwarded-host",
+        "forwarded",
+    ] {
+        head.remove(name);
+    }
+    let host = head.header("host").map(|v| v.to_string());
+
+    // RFC 7239, IPv6 地址需要加方括号和引号
+    let node = match visitor {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let mut forwarded = format!("for={};proto={}", node, proto);
+    head.append("X-Forwarded-For", visitor.to_string());
+    head.append("X-Forwarded-Proto", proto.to_string());
+    if let Some(host) = host {
+        let quoted = host.replace('\\', "\\\\").replace('"', "\\\"");
+        forwarded.push_str(&format!(";host=\"{}\"", quoted));
+        head.append("X-Forwarded-Host", host);
     }
+    head.append("Forwarded", forwarded);
 }
 
-fn eq_host(s: &[u8]) -> bool {
-    s.len() == 4
-        && (s[0] | 32) == b'h'
-        && (s[1] | 32) == b'o'
-        && (s[2] | 32) == b's'
-        && (s[3] | 32) == b't'
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    const VISITOR: [u8; 4] = [10, 0, 0, 1];
+
+    // 在后台运行 copy_http, 返回 (访问者一端, 后端一端, 任务)
+    fn start() -> (DuplexStream, DuplexStream, JoinHandle<crate::Result<()>>) {
+        let (visitor, mut visitor_proxy) = duplex(64 * 1024);
+        let (mut backend_proxy, backend) = duplex(64 * 1024);
+        let task = tokio::spawn(async move {
+            copy_http(&mut visitor_proxy, &mut backend_proxy, Vec::new(), |head| {
+                set_forwarded(head, VISITOR.into(), "https")
+            })
+            .await
+        });
+        (visitor, backend, task)
+    }
+
+    // 访问者发送 request 后关闭, 后端读完后返回 response, 返回 (后端收到的, 访问者收到的)
+    async fn forward(request: &str, response: &str) -> (String, String) {
+        let (mut visitor, mut backend, task) = start();
+        visitor.write_all(request.as_bytes()).await.unwrap();
+        visitor.shutdown().await.unwrap();
+        let received = read_all(&mut backend).await;
+        backend.write_all(response.as_bytes()).await.unwrap();
+        backend.shutdown().await.unwrap();
+        let sent = read_all(&mut visitor).await;
+        task.await.unwrap().unwrap();
+        (received, sent)
+    }
+
+    async fn read_all(stream: &mut DuplexStream) -> String {
+        let mut s = String::new();
+        stream.read_to_string(&mut s).await.unwrap();
+        s
+    }
+
+    // 读取到空行为止
+    async fn read_head(stream: &mut DuplexStream) -> String {
+        let mut buf = Vec::new();
+        while !buf.ends_with(b"\r\n\r\n") {
+            buf.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
+    // 经过 set_forwarded 修改后发给后端的请求
+    fn rewritten(start: &str, headers: &str, body: &str) -> String {
+        format!(
+            "{}\r\nHost: a.com\r\n{}X-Forwarded-For: 10.0.0.1\r\nX-Forwarded-Proto: https\r\n\
+             X-Forwarded-Host: a.com\r\nForwarded: for=10.0.0.1;proto=https;host=\"a.com\"\r\n\r\n{}",
+            start, headers, body
+        )
+    }
+
+    fn parse(head: &str) -> RequestHead {
+        RequestHead::parse(head.as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        let request = "GET /a HTTP/1.1\r\nHost: a.com\r\n\r\n\
+                       POST /b HTTP/1.1\r\nHost: a.com\r\nContent-Length: 5\r\n\r\nhello\
+                       GET /c HTTP/1.1\r\nHost: a.com\r\n\r\n";
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+                        HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n\
+                        HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nc";
+        let (received, sent) = forward(request, response).await;
+        let expected = rewritten("GET /a HTTP/1.1", "", "")
+            + &rewritten("POST /b HTTP/1.1", "Content-Length: 5\r\n", "hello")
+            + &rewritten("GET /c HTTP/1.1", "", "");
+        assert_eq!(received, expected);
+        assert_eq!(sent, response);
+    }
+
+    #[tokio::test]
+    async fn chunked_body_with_trailers() {
+        let body = "5;ext=1\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let request = format!(
+            "POST /a HTTP/1.1\r\nHost: a.com\r\nTransfer-Encoding: chunked\r\n\r\n{}\
+             GET /b HTTP/1.1\r\nHost: a.com\r\n\r\n",
+            body
+        );
+        let response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n0\r\n\r\n\
+                        HTTP/1.1 204 No Content\r\n\r\n";
+        let (received, sent) = forward(&request, response).await;
+        let expected = rewritten("POST /a HTTP/1.1", "Transfer-Encoding: chunked\r\n", body)
+            + &rewritten("GET /b HTTP/1.1", "", "");
+        assert_eq!(received, expected);
+        assert_eq!(sent, response);
+    }
+
+    #[tokio::test]
+    async fn head_response_has_no_body() {
+        let request =
+            "HEAD /a HTTP/1.1\r\nHost: a.com\r\n\r\nGET /b HTTP/1.1\r\nHost: a.com\r\n\r\n";
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n\
+                        HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb";
+        let (_, sent) = forward(request, response).await;
+        assert_eq!(sent, response);
+    }
+
+    #[tokio::test]
+    async fn spoofed_headers_are_removed() {
+        let request = "GET / HTTP/1.1\r\nHost: a.com\r\nX-Forwarded-For: 6.6.6.6\r\n\
+                       x-forwarded-proto: http\r\nX-Forwarded-Host: evil.com\r\n\
+                       Forwarded: for=6.6.6.6\r\n\r\n";
+        let (received, _) = forward(request, "").await;
+        assert_eq!(received, rewritten("GET / HTTP/1.1", "", ""));
+    }
+
+    #[test]
+    fn forwarded_quotes_ipv6_and_host() {
+        let mut head = parse("GET / HTTP/1.1\r\nHost: a\"b.com\r\n\r\n");
+        set_forwarded(&mut head, Ipv6Addr::LOCALHOST.into(), "https");
+        assert_eq!(head.header("x-forwarded-for"), Some("::1"));
+        assert_eq!(
+            head.header("forwarded"),
+            Some("for=\"[::1]\";proto=https;host=\"a\\\"b.com\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_upgrade_keeps_rewriting() {
+        let (mut visitor, mut backend, task) = start();
+        let request =
+            "GET /ws HTTP/1.1\r\nHost: a.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n\
+                       GET /b HTTP/1.1\r\nHost: a.com\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n";
+        visitor.write_all(request.as_bytes()).await.unwrap();
+        visitor.shutdown().await.unwrap();
+        let head = read_head(&mut backend).await;
+        assert!(head.starts_with("GET /ws "));
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        backend.write_all(response.as_bytes()).await.unwrap();
+        assert_eq!(
+            read_all(&mut backend).await,
+            rewritten("GET /b HTTP/1.1", "", "")
+        );
+        backend.shutdown().await.unwrap();
+        assert_eq!(read_all(&mut visitor).await, response);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepted_upgrade_copies_raw() {
+        let (mut visitor, mut backend, task) = start();
+        let request =
+            "GET /ws HTTP/1.1\r\nHost: a.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        let data = "GET /b HTTP/1.1\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n";
+        visitor.write_all(request.as_bytes()).await.unwrap();
+        visitor.write_all(data.as_bytes()).await.unwrap();
+        visitor.shutdown().await.unwrap();
+        read_head(&mut backend).await;
+        let response = "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\npong";
+        backend.write_all(response.as_bytes()).await.unwrap();
+        assert_eq!(read_all(&mut backend).await, data);
+        backend.shutdown().await.unwrap();
+        assert_eq!(read_all(&mut visitor).await, response);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn ambiguous_framing_gets_400() {
+        let request = "GET /a HTTP/1.1\r\nHost: a.com\r\n\r\n\
+                       POST /b HTTP/1.1\r\nHost: a.com\r\nContent-Length: 5\r\n\
+                       Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na";
+        let (received, sent) = forward(request, response).await;
+        assert_eq!(received, rewritten("GET /a HTTP/1.1", "", ""));
+        let bad_request =
+            "HTTP/1.1 400 Bad Request\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+        assert_eq!(sent, response.to_string() + bad_request);
+    }
+
+    #[tokio::test]
+    async fn oversized_chunk_gets_400() {
+        let request = "POST /a HTTP/1.1\r\nHost: a.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       ffffffffffffffff\r\nGET /b HTTP/1.1\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n";
+        let (received, sent) = forward(request, "").await;
+        let expected = rewritten("POST /a HTTP/1.1", "Transfer-Encoding: chunked\r\n", "");
+        assert_eq!(received, expected);
+        assert_eq!(
+            sent,
+            "HTTP/1.1 400 Bad Request\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_chunk_size() {
+        assert_eq!(chunk_size(b"a"), Some(10));
+        assert_eq!(chunk_size(b"0;ext=1"), Some(0));
+        assert_eq!(chunk_size(b"ffffffffffffffff"), Some(u64::MAX));
+        assert_eq!(chunk_size(b"10000000000000000"), None);
+        assert_eq!(chunk_size(b"+1"), None);
+        assert_eq!(chunk_size(b""), None);
+    }
+
+    #[tokio::test]
+    async fn repeated_content_length_is_merged() {
+        let request = "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Length: 2, 2\r\n\r\nhi";
+        let (received, _) = forward(request, "").await;
+        let expected = rewritten("POST / HTTP/1.1", "Content-Length: 2\r\n", "hi");
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn request_body_length() {
+        let body = |headers: &str| parse(&format!("POST / HTTP/1.1\r\n{}\r\n", headers)).body();
+        assert!(matches!(body(""), Some(Body::Empty)));
+        assert!(matches!(
+            body("Content-Length: 5\r\n"),
+            Some(Body::Length(5))
+        ));
+        assert!(matches!(
+            body("Content-Length: 5\r\nContent-Length: 5\r\n"),
+            Some(Body::Length(5))
+        ));
+        assert!(matches!(
+            body("Transfer-Encoding: chunked\r\n"),
+            Some(Body::Chunked)
+        ));
+        assert!(matches!(
+            body("Transfer-Encoding: gzip, Chunked\r\n"),
+            Some(Body::Chunked)
+        ));
+        assert!(body("Content-Length: 5\r\nContent-Length: 6\r\n").is_none());
+        assert!(body("Content-Length: 5, 6\r\n").is_none());
+        assert!(body("Content-Length: +5\r\n").is_none());
+        assert!(body("Content-Length:\r\n").is_none());
+        assert!(body("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n").is_none());
+        assert!(body("Transfer-Encoding: xchunked\r\n").is_none());
+        assert!(body("Transfer-Encoding: chunked, gzip\r\n").is_none());
+        assert!(body("Transfer-Encoding: chunked, chunked\r\n").is_none());
+        assert!(body("Transfer-Encoding:\r\n").is_none());
+    }
+
+    #[test]
+    fn malformed_request_head() {
+        let parse = |head: &str| RequestHead::parse(head.as_bytes());
+        assert!(parse("GET / HTTP/1.1\r\nHost: a.com\r\n\r\n").is_some());
+        assert!(parse("GET / HTTP/1.1 x\r\n\r\n").is_none());
+        assert!(parse("GET /\r\n\r\n").is_none());
+        assert!(parse("GET / HTTP/1.1\r\nContent-Length : 5\r\n\r\n").is_none());
+        assert!(parse("GET / HTTP/1.1\r\nHost: a.com\r\n folded\r\n\r\n").is_none());
+        assert!(parse("GET / HTTP/1.1\r\nX: a\nContent-Length: 5\r\n\r\n").is_none());
+        assert!(parse("GET / HTTP/1.1\r\nno colon\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn strip_host_port() {
+        assert_eq!(strip_port("a.com"), "a.com");
+        assert_eq!(strip_port("a.com:8443"), "a.com");
+        assert_eq!(strip_port("a.com:"), "a.com");
+        assert_eq!(strip_port("[::1]:8443"), "::1");
+        assert_eq!(strip_port("[::1]"), "::1");
+        assert_eq!(strip_port("a.com:x"), "a.com:x");
+    }
 }
@@ -2,9 +2,21 @@ use error::*;
 
 #[macro_use]
 mod error;
+mod access_log;
+mod acl;
+mod admin;
+mod auth;
+mod cidr;
 pub mod client;
+pub mod ctl;
+mod destination;
+mod error_page;
 mod http;
+mod limit;
+mod metrics;
 mod protocol;
+mod proxy;
 pub mod server;
 mod shared;
+mod timeout;
 mod util;
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// 令牌桶速率限制, 格式为 "每秒数量[/突发数量]", 突发数量默认等于每秒数量
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+}
+
+#[derive(Debug)]
+pub struct InvalidRateLimit;
+
+impl Display for InvalidRateLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("wrong format", f)
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = InvalidRateLimit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, burst) = match s.split_once('/') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let rate: f64 = rate.trim().parse().map_err(|_| InvalidRateLimit)?;
+        let burst: f64 = match burst {
+            Some(burst) => burst.trim().parse().map_err(|_| InvalidRateLimit)?,
+            None => rate.max(1.0),
+        };
+        if !(rate > 0.0 && burst >= 1.0 && rate.is_finite() && burst.is_finite()) {
+            return Err(InvalidRateLimit);
+        }
+        Ok(Self { rate, burst })
+    }
+}
+
+// 访问者连接的限制, 分别按域名和访问者地址计算
+pub struct Limits {
+    domain: Arc<Limiter>,
+    ip: Arc<Limiter>,
+}
+
+impl Limits {
+    pub fn new(
+        domain_rate: Option<RateLimit>,
+        domain_max: Option<usize>,
+        ip_rate: Option<RateLimit>,
+        ip_max: Option<usize>,
+    ) -> Self {
+        Self {
+            domain: Arc::new(Limiter::new(domain_rate, domain_max)),
+            ip: Arc::new(Limiter::new(ip_rate, ip_max)),
+        }
+    }
+
+    // 获取一个连接的许可, 许可释放前计入同时连接数. 超过限制时返回建议的重试等待秒数.
+    // 两个限制都通过后才消耗令牌, 按地址和域名的顺序加锁
+    pub fn acquire(&self, domain: &str, ip: IpAddr) -> Result<(Permit, Permit), u64> {
+        let ip = ip.to_string();
+        let now = Instant::now();
+        let mut ip_state = self.ip.state.lock().unwrap();
+        let mut domain_state = self.domain.state.lock().unwrap();
+        self.ip.check(&mut ip_state, &ip, now)?;
+        self.domain.check(&mut domain_state, domain, now)?;
+        Ok((
+            self.ip.take(&mut ip_state, &ip),
+            self.domain.take(&mut domain_state, domain),
+        ))
+    }
+
+    // 删除空闲的计数, 避免访问过的域名和地址一直占用内存
+    pub fn cleanup(&self) {
+        self.domain.cleanup();
+        self.ip.cleanup();
+    }
+}
+
+struct Limiter {
+    rate: Option<RateLimit>,
+    max: Option<usize>,
+    state: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    active: usize, // 同时连接数
+}
+
+impl Limiter {
+    fn new(rate: Option<RateLimit>, max: Option<usize>) -> Self {
+        Self {
+            rate,
+            max,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // 补充令牌并检查是否超过限制, 超过时返回建议的重试等待秒数
+    fn check(
+        &self,
+        state: &mut HashMap<String, Bucket>,
+        key: &str,
+        now: Instant,
+    ) -> Result<(), u64> {
+        if self.rate.is_none() && self.max.is_none() {
+            return Ok(());
+        }
+        let bucket = state.entry(key.to_string()).or_insert_with(|| Bucket {
+            tokens: self.rate.map_or(0.0, |v| v.burst),
+            updated: now,
+            active: 0,
+        });
+        if self.max.is_some_and(|max| bucket.active >= max) {
+            return Err(1);
+        }
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate.rate).min(rate.burst);
+            bucket.updated = now;
+            if bucket.tokens < 1.0 {
+                return Err(((1.0 - bucket.tokens) / rate.rate).ceil() as u64);
+            }
+        }
+        Ok(())
+    }
+
+    // check 通过后消耗一个令牌, 并计入同时连接数
+    fn take(self: &Arc<Self>, state: &mut HashMap<String, Bucket>, key: &str) -> Permit {
+        let bucket = match state.get_mut(key) {
+            Some(bucket) => bucket,
+            None => return Permit(None),
+        };
+        if self.rate.is_some() {
+            bucket.tokens -= 1.0;
+        }
+        bucket.active += 1;
+        Permit(Some((self.clone(), key.to_string())))
+    }
+
+    fn release(&self, key: &str) {
+        if let Some(bucket) = self.state.lock().unwrap().get_mut(key) {
+            bucket.active -= 1;
+        }
+    }
+
+    // 删除没有连接并且令牌已补满的计数
+    fn cleanup(&self) {
+        let now = Instant::now();
+        let rate = self.rate;
+        self.state.lock().unwrap().retain(|_, v| {
+            let full = match rate {
+                Some(rate) => {
+                    let elapsed = now.duration_since(v.updated).as_secs_f64();
+                    v.tokens + elapsed * rate.rate >= rate.burst
+                }
+                None => true,
+            };
+            v.active > 0 || !full
+        });
+    }
+}
+
+// 连接许可, 释放时减少同时连接数
+pub struct Permit(Option<(Arc<Limiter>, String)>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((limiter, key)) = &self.0 {
+            limiter.release(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    impl Limiter {
+        fn acquire(self: &Arc<Self>, key: &str) -> Result<Permit, u64> {
+            let mut state = self.state.lock().unwrap();
+            self.check(&mut state, key, Instant::now())?;
+            Ok(self.take(&mut state, key))
+        }
+    }
+
+    fn limiter(rate: &str, max: Option<usize>) -> Arc<Limiter> {
+        Arc::new(Limiter::new(Some(rate.parse().unwrap()), max))
+    }
+
+    // 把上次补充令牌的时间往前调, 模拟时间流逝
+    fn elapse(limiter: &Limiter, key: &str, secs: u64) {
+        let mut state = limiter.state.lock().unwrap();
+        state.get_mut(key).unwrap().updated -= Duration::from_secs(secs);
+    }
+
+    fn active(limiter: &Limiter, key: &str) -> usize {
+        limiter
+            .state
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |v| v.active)
+    }
+
+    #[test]
+    fn parse_rate_limit() {
+        let limit: RateLimit = "10".parse().unwrap();
+        assert_eq!((limit.rate, limit.burst), (10.0, 10.0));
+        let limit: RateLimit = "0.5".parse().unwrap();
+        assert_eq!((limit.rate, limit.burst), (0.5, 1.0));
+        let limit: RateLimit = "5/20".parse().unwrap();
+        assert_eq!((limit.rate, limit.burst), (5.0, 20.0));
+        for v in ["", "0", "-1", "1/0.5", "1/", "x", "inf", "NaN", "1/inf"] {
+            assert!(v.parse::<RateLimit>().is_err(), "{}", v);
+        }
+    }
+
+    #[test]
+    fn burst() {
+        let limiter = limiter("1/3", None);
+        let permits: Vec<_> = (0..3).map(|_| limiter.acquire("a").unwrap()).collect();
+        assert_eq!(limiter.acquire("a").err(), Some(1));
+        // 每个键单独计算
+        assert!(limiter.acquire("b").is_ok());
+        drop(permits);
+        // 释放许可不归还令牌
+        assert!(limiter.acquire("a").is_err());
+    }
+
+    #[test]
+    fn retry_after() {
+        let limiter = limiter("0.5", None);
+        assert!(limiter.acquire("a").is_ok());
+        assert_eq!(limiter.acquire("a").err(), Some(2));
+
+        let limiter = self::limiter("0.25/2", None);
+        assert!(limiter.acquire("a").is_ok());
+        assert!(limiter.acquire("a").is_ok());
+        assert_eq!(limiter.acquire("a").err(), Some(4));
+        elapse(&limiter, "a", 3);
+        assert_eq!(limiter.acquire("a").err(), Some(1));
+    }
+
+    #[test]
+    fn refill() {
+        let limiter = limiter("2/4", None);
+        for _ in 0..4 {
+            assert!(limiter.acquire("a").is_ok());
+        }
+        assert!(limiter.acquire("a").is_err());
+        elapse(&limiter, "a", 1);
+        assert!(limiter.acquire("a").is_ok());
+        assert!(limiter.acquire("a").is_ok());
+        assert!(limiter.acquire("a").is_err());
+        // 补充的令牌不超过突发数量
+        elapse(&limiter, "a", 60);
+        for _ in 0..4 {
+            assert!(limiter.acquire("a").is_ok());
+        }
+        assert!(limiter.acquire("a").is_err());
+    }
+
+    #[test]
+    fn release_on_drop() {
+        let limiter = Arc::new(Limiter::new(None, Some(2)));
+        let first = limiter.acquire("a").unwrap();
+        let second = limiter.acquire("a").unwrap();
+        assert_eq!(active(&limiter, "a"), 2);
+        assert_eq!(limiter.acquire("a").err(), Some(1));
+        drop(first);
+        assert_eq!(active(&limiter, "a"), 1);
+        let third = limiter.acquire("a").unwrap();
+        drop(second);
+        drop(third);
+        assert_eq!(active(&limiter, "a"), 0);
+        limiter.cleanup();
+        assert!(limiter.state.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cleanup_keeps_busy_keys() {
+        let limiter = limiter("1/2", Some(5));
+        let permit = limiter.acquire("a").unwrap();
+        drop(limiter.acquire("b").unwrap());
+        limiter.cleanup();
+        // "a" 有连接, "b" 的令牌未补满
+        assert_eq!(limiter.state.lock().unwrap().len(), 2);
+        drop(permit);
+        elapse(&limiter, "a", 2);
+        limiter.cleanup();
+        let state = limiter.state.lock().unwrap();
+        assert!(!state.contains_key("a") && state.contains_key("b"));
+    }
+
+    #[test]
+    fn limits_by_domain_and_ip() {
+        let limits = Limits::new(None, Some(1), None, Some(2));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let permit = limits.acquire("a.com", ip).unwrap();
+        assert!(limits
+            .acquire("a.com", "10.0.0.2".parse().unwrap())
+            .is_err());
+        let other = limits.acquire("b.com", ip).unwrap();
+        // 地址的同时连接数已满
+        assert!(limits.acquire("c.com", ip).is_err());
+        // 域名检查失败时释放已获得的地址许可
+        drop(other);
+        assert!(limits.acquire("a.com", ip).is_err());
+        assert!(limits.acquire("c.com", ip).is_ok());
+        drop(permit);
+        assert!(limits.acquire("a.com", ip).is_ok());
+    }
+
+    #[test]
+    fn rejected_by_domain_keeps_ip_token() {
+        let limits = Limits::new(None, Some(1), Some("1".parse().unwrap()), None);
+        let _permit = limits
+            .acquire("a.com", "10.0.0.1".parse().unwrap())
+            .unwrap();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(limits.acquire("a.com", ip).err(), Some(1));
+        assert!(limits.acquire("b.com", ip).is_ok());
+        assert!(limits.acquire("c.com", ip).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use log::{debug, error, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+// 读取请求的超时时间, 避免连接一直不发送完整的请求头
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 耗时直方图默认的桶（秒）
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+// 计数器
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// 仪表
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // 加一, 返回的守卫释放时减一
+    pub fn track(self: &Arc<Self>) -> GaugeGuard {
+        self.inc();
+        GaugeGuard(self.clone())
+    }
+}
+
+pub struct GaugeGuard(Arc<Gauge>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// 直方图
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum: Mutex<f64>,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: Mutex::new(0.0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if v <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        *self.sum.lock().unwrap() += v;
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new(DURATION_BUCKETS)
+    }
+}
+
+// 按标签值分组的指标
+pub struct Family<T> {
+    labels: &'static [&'static str],
+    map: Mutex<BTreeMap<Vec<String>, Arc<T>>>,
+}
+
+impl<T: Default> Family<T> {
+    pub fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            map: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // 获取标签值对应的指标, 不存在则创建, values 与 labels 一一对应
+    pub fn get(&self, values: &[&str]) -> Arc<T> {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.map.lock().unwrap().entry(key).or_default().clone()
+    }
+
+    // 所有标签值及对应的指标
+    pub fn values(&self) -> Vec<(Vec<String>, Arc<T>)> {
+        let map = self.map.lock().unwrap();
+        map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    fn snapshot(&self) -> Vec<(String, Arc<T>)> {
+        let map = self.map.lock().unwrap();
+        map.iter()
+            .map(|(values, v)| (format_labels(self.labels, values), v.clone()))
+            .collect()
+    }
+}
+
+fn format_labels(labels: &[&str], values: &[String]) -> String {
+    let mut s = String::new();
+    for (i, (name, value)) in labels.iter().zip(values).enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(s, "{}=\"{}\"", name, value);
+    }
+    s
+}
+
+fn join_labels(a: &str, b: &str) -> String {
+    match (a.is_empty(), b.is_empty()) {
+        (true, _) => b.to_string(),
+        (_, true) => a.to_string(),
+        _ => format!("{},{}", a, b),
+    }
+}
+
+// Prometheus 文本格式编码
+#[derive(Default)]
+pub struct Encoder(String);
+
+impl Encoder {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: impl std::fmt::Display) {
+        match labels.is_empty() {
+            true => {
+                let _ = writeln!(self.0, "{} {}", name, value);
+            }
+            false => {
+                let _ = writeln!(self.0, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    fn histogram_samples(&mut self, name: &str, labels: &str, h: &Histogram) {
+        let bucket = format!("{}_bucket", name);
+        for (bound, v) in h.bounds.iter().zip(&h.buckets) {
+            let le = join_labels(labels, &format!("le=\"{}\"", bound));
+            self.sample(&bucket, &le, v.load(Ordering::Relaxed));
+        }
+        let count = h.count.load(Ordering::Relaxed);
+        self.sample(&bucket, &join_labels(labels, "le=\"+Inf\""), count);
+        self.sample(&format!("{}_sum", name), labels, *h.sum.lock().unwrap());
+        self.sample(&format!("{}_count", name), labels, count);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, v: &Counter) {
+        self.header(name, help, "counter");
+        self.sample(name, "", v.get());
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, v: i64) {
+        self.header(name, help, "gauge");
+        self.sample(name, "", v);
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, v: &Histogram) {
+        self.header(name, help, "histogram");
+        self.histogram_samples(name, "", v);
+    }
+
+    pub fn counter_family(&mut self, name: &str, help: &str, family: &Family<Counter>) {
+        self.header(name, help, "counter");
+        for (labels, v) in family.snapshot() {
+            self.sample(name, &labels, v.get());
+        }
+    }
+
+    pub fn gauge_family(&mut self, name: &str, help: &str, family: &Family<Gauge>) {
+        self.header(name, help, "gauge");
+        for (labels, v) in family.snapshot() {
+            self.sample(name, &labels, v.get());
+        }
+    }
+
+    pub fn histogram_family(&mut self, name: &str, help: &str, family: &Family<Histogram>) {
+        self.header(name, help, "histogram");
+        for (labels, v) in family.snapshot() {
+            self.histogram_samples(name, &labels, &v);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.0
+    }
+}
+
+// 统计读写字节数的连接
+pub struct Metered<S> {
+    inner: S,
+    read: Arc<Counter>,
+    written: Arc<Counter>,
+    total: (u64, u64), // 本连接读写的字节数
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, read: Arc<Counter>, written: Arc<Counter>) -> Self {
+        Self {
+            inner,
+            read,
+            written,
+            total: (0, 0),
+        }
+    }
+
+    // 本连接读取和写入的字节数
+    pub fn total(&self) -> (u64, u64) {
+        self.total
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let n = (buf.filled().len() - before) as u64;
+            self.read.add(n);
+            self.total.0 += n;
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.written.add(n as u64);
+            self.total.1 += n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// 在 addr 上提供 Prometheus 指标, 每个请求调用 render 生成响应内容
+pub async fn serve<F>(addr: SocketAddr, render: F) -> crate::Result<()>
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(err!("cannot bind {}", addr))?;
+    info!("metrics started at {}", addr);
+    let render = Arc::new(render);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("metrics connection from {}", addr);
+                    let render = render.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_metrics(stream, &*render).await {
+                            error!("{}", e);
+                        }
+                    });
+                }
+                Err(err) => error!("metrics accept error: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_metrics(
+    mut stream: TcpStream,
+    render: &(dyn Fn() -> String + Send + Sync),
+) -> crate::Result<()> {
+    // 不解析请求, 读到请求头结束即返回指标
+    let read = async {
+        let mut buf = vec![0; 1024];
+        let mut read = 0;
+        while !buf[..read].windows(4).any(|v| v == b"\r\n\r\n") && read < buf.len() {
+            let n = stream.read(&mut buf[read..]).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+            read += n;
+        }
+        Ok::<_, io::Error>(true)
+    };
+    let complete = timeout(READ_TIMEOUT, read)
+        .await
+        .map_err(err!("read metrics request timeout"))?
+        .map_err(err!())?;
+    if !complete {
+        return Ok(());
+    }
+
+    let body = render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(err!())?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
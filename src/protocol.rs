@@ -1,21 +1,51 @@
+use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 
 use log::debug;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::auth::Auth;
+
+// UDP 数据报最大长度, 需保证序列化后的消息长度不超过 u16
+pub const MAX_DATAGRAM_SIZE: usize = 65000;
+
+// 转发目标
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Target {
+    Domain(String), // 域名
+    Port(u16),      // 服务端 TCP 端口
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Domain(domain) => Display::fmt(domain, f),
+            Target::Port(port) => write!(f, "tcp:{}", port),
+        }
+    }
+}
+
 // 服务端发给客户端的转发请求
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub key: Vec<u8>,
     // 此转发在服务端的唯一标识
-    pub domain: String, // 转发对应的域名
+    pub target: Target,      // 转发对应的目标
+    pub visitor: SocketAddr, // 访问者地址
+    pub local: SocketAddr,   // 访问者连接的服务端地址
 }
 
 impl Request {
-    pub fn new(key: Vec<u8>, domain: String) -> Self {
-        Self { key, domain }
+    pub fn new(key: Vec<u8>, target: Target, visitor: SocketAddr, local: SocketAddr) -> Self {
+        Self {
+            key,
+            target,
+            visitor,
+            local,
+        }
     }
 }
 
@@ -26,12 +56,23 @@ pub enum Protocol {
     Register {
         // 客户端想要转发的域名
         domains: Vec<String>,
+        // 客户端想要转发的服务端 TCP 端口, 0 表示由服务端分配
+        ports: Vec<u16>,
+        // 客户端想要转发的服务端 UDP 端口, 0 表示由服务端分配
+        udp_ports: Vec<u16>,
+        // 客户端要求服务端对域名做的访问认证, 服务端配置了该域名的认证时以服务端为准
+        auth: Vec<(String, Auth)>,
     },
 
     // 客户端注册成功
-    Ok,
+    Ok {
+        // 服务端实际绑定的 TCP 端口, 与 Register 中的 ports 一一对应
+        ports: Vec<u16>,
+        // 服务端实际绑定的 UDP 端口, 与 Register 中的 udp_ports 一一对应
+        udp_ports: Vec<u16>,
+    },
 
-    // 客户端注册失败，至少一个域名已被其他客户端使用
+    // 客户端注册失败，至少一个域名已被其他客户端使用，或 TCP 端口无法绑定
     Error,
 
     // 转发请求
@@ -42,22 +83,62 @@ pub enum Protocol {
         key: Vec<u8>,
     },
 
+    // 转发失败, 客户端无法连接目的地址, 通过注册连接发送
+    Reject {
+        key: Vec<u8>,
+        reason: String,
+    },
+
+    // 转发失败, 客户端没有该转发目标的配置, 通过注册连接发送
+    NotFound {
+        key: Vec<u8>,
+    },
+
     Ping,
 
     Pong,
+
+    // UDP 数据报, 双向发送, port 为服务端 UDP 端口, session 为服务端分配的对端会话标识
+    UdpData {
+        port: u16,
+        session: u32,
+        data: Vec<u8>,
+    },
+
+    // UDP 会话空闲超时, 服务端发给客户端, 客户端释放对应的本地 socket
+    UdpClose {
+        port: u16,
+        session: u32,
+    },
+
+    // 即将退出, 双向发送. 服务端发送时客户端应重新连接, 客户端发送时服务端不再向其转发新连接,
+    // 正在转发的连接不受影响
+    GoingAway,
 }
 
+#[derive(Debug)]
+struct MessageTooLarge(u64);
+
+impl Display for MessageTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message too large: {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
 impl Protocol {
     // 发送, 非取消安全, 不能用于 tokio::select!
     pub async fn send(&self, stream: &mut (impl AsyncWrite + Unpin)) -> crate::Result<()> {
         debug!("send {:?}", self);
         let len = bincode::serialized_size(self).map_err(err!())?;
-        debug_assert!(len + 2 < u16::MAX as u64);
+        if len + 2 > u16::MAX as u64 {
+            return Err(MessageTooLarge(len)).map_err(err!());
+        }
 
         let mut buf = Vec::with_capacity(len as usize + 2);
-        unsafe { buf.set_len(buf.capacity()) };
-        buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
-        buf[2..].copy_from_slice(&bincode::serialize(self).map_err(err!())?);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+        buf.extend_from_slice(&bincode::serialize(self).map_err(err!())?);
         stream.write_all(&buf).await.map_err(err!("write_all"))
     }
 }
@@ -100,8 +181,7 @@ impl Receiver {
                     *read += n;
                     if *read == 2 {
                         let len = u16::from_be_bytes([buf[0], buf[1]]);
-                        let mut buf = Vec::with_capacity(len as usize);
-                        unsafe { buf.set_len(buf.capacity()) };
+                        let buf = vec![0; len as usize];
                         self.state = State::ReadPayload { buf, read: 0 }
                     } else if n == 0 {
                         return if *read == 0 {
@@ -0,0 +1,372 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{from_utf8, FromStr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// PROXY protocol v2 头的固定签名
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// v1 头的最大长度, 包括结尾的 "\r\n"
+const MAX_V1_LENGTH: usize = 107;
+
+// PROXY protocol 版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyVersion {
+    V1, // 文本格式
+    V2, // 二进制格式
+}
+
+#[derive(Debug)]
+pub struct InvalidProxyVersion;
+
+impl Display for InvalidProxyVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("expect v1 or v2", f)
+    }
+}
+
+impl FromStr for ProxyVersion {
+    type Err = InvalidProxyVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(ProxyVersion::V1),
+            "v2" => Ok(ProxyVersion::V2),
+            _ => Err(InvalidProxyVersion),
+        }
+    }
+}
+
+impl ProxyVersion {
+    // 生成 PROXY 头, addrs 为 (源地址, 目的地址), None 表示连接不是代理的 (如健康检查)
+    pub fn header(self, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+        let addrs = addrs.map(|(src, dst)| same_family(src, dst));
+        match self {
+            ProxyVersion::V1 => match addrs {
+                Some((src, dst)) => {
+                    let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                    format!(
+                        "PROXY {} {} {} {} {}\r\n",
+                        family,
+                        src.ip(),
+                        dst.ip(),
+                        src.port(),
+                        dst.port()
+                    )
+                    .into_bytes()
+                }
+                None => b"PROXY UNKNOWN\r\n".to_vec(),
+            },
+            ProxyVersion::V2 => {
+                let mut buf = SIGNATURE.to_vec();
+                match addrs {
+                    Some((src, dst)) => {
+                        // 版本 2, PROXY 命令
+                        buf.push(0x21);
+                        let (family, len) = if src.is_ipv4() {
+                            (0x11, 12)
+                        } else {
+                            (0x21, 36)
+                        };
+                        buf.push(family);
+                        buf.extend_from_slice(&(len as u16).to_be_bytes());
+                        push_ip(&mut buf, src.ip());
+                        push_ip(&mut buf, dst.ip());
+                        buf.extend_from_slice(&src.port().to_be_bytes());
+                        buf.extend_from_slice(&dst.port().to_be_bytes());
+                    }
+                    None => {
+                        // 版本 2, LOCAL 命令, 没有地址
+                        buf.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                    }
+                }
+                buf
+            }
+        }
+    }
+}
+
+// 源地址和目的地址需为同一地址族, 不同时把 IPv4 地址转换为 IPv4 映射的 IPv6 地址
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if src.is_ipv4() == dst.is_ipv4() {
+        return (src, dst);
+    }
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    (to_v6(src), to_v6(dst))
+}
+
+fn push_ip(buf: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidProxyHeader;
+
+impl Display for InvalidProxyHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("invalid proxy protocol header", f)
+    }
+}
+
+impl std::error::Error for InvalidProxyHeader {}
+
+// 读取 v1 或 v2 格式的 PROXY 头, 只读取头部, 之后的数据 (如 TLS 握手) 留在连接中.
+// 返回 (源地址, 目的地址), 头中没有地址 (v1 的 UNKNOWN, v2 的 LOCAL 或非 TCP 地址) 时返回 None
+pub async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> crate::Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut buf = vec![0; 8];
+    stream.read_exact(&mut buf).await.map_err(err!())?;
+    if buf.starts_with(b"PROXY ") {
+        // 逐字节读取到 "\r\n", 避免多读
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= MAX_V1_LENGTH {
+                return Err(InvalidProxyHeader).map_err(err!());
+            }
+            buf.push(stream.read_u8().await.map_err(err!())?);
+        }
+        parse_v1(&buf[..buf.len() - 2]).map_err(err!())
+    } else if buf == SIGNATURE[..8] {
+        buf.resize(16, 0);
+        stream.read_exact(&mut buf[8..]).await.map_err(err!())?;
+        if buf[..12] != SIGNATURE || buf[12] >> 4 != 2 {
+            return Err(InvalidProxyHeader).map_err(err!());
+        }
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.map_err(err!())?;
+        parse_v2(buf[12] & 0x0f, buf[13], &body).map_err(err!())
+    } else {
+        Err(InvalidProxyHeader).map_err(err!())
+    }
+}
+
+// 格式为 "PROXY TCP4 源地址 目的地址 源端口 目的端口"
+fn parse_v1(line: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, InvalidProxyHeader> {
+    let line = from_utf8(line).map_err(|_| InvalidProxyHeader)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr, InvalidProxyHeader> {
+                let ip: IpAddr = ip.parse().map_err(|_| InvalidProxyHeader)?;
+                let port = port.parse().map_err(|_| InvalidProxyHeader)?;
+                match ip.is_ipv4() == (*family == "TCP4") {
+                    true => Ok(SocketAddr::new(ip, port)),
+                    false => Err(InvalidProxyHeader),
+                }
+            };
+            Ok(Some((parse(src, src_port)?, parse(dst, dst_port)?)))
+        }
+        _ => Err(InvalidProxyHeader),
+    }
+}
+
+// command 为 0 表示 LOCAL, 1 表示 PROXY, 地址之后的 TLV 被忽略
+fn parse_v2(
+    command: u8,
+    family: u8,
+    body: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>, InvalidProxyHeader> {
+    match command {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(InvalidProxyHeader),
+    }
+    match family {
+        // TCP over IPv4
+        0x11 if body.len() >= 12 => {
+            let ip = |i: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    body[i],
+                    body[i + 1],
+                    body[i + 2],
+                    body[i + 3],
+                ))
+            };
+            let port = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            )))
+        }
+        // TCP over IPv6
+        0x21 if body.len() >= 36 => {
+            let ip = |i: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[i..i + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            )))
+        }
+        0x11 | 0x21 => Err(InvalidProxyHeader),
+        // UDP、unix 域 socket 或未指定, 不使用其中的地址
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // 读取 PROXY 头, 返回结果和连接中剩余的数据
+    async fn read(data: &[u8]) -> (crate::Result<Option<(SocketAddr, SocketAddr)>>, Vec<u8>) {
+        let mut input = data;
+        let re = read_header(&mut input).await;
+        (re, input.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (re, rest) = read(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 443\r\n\x16\x03").await;
+        assert_eq!(
+            re.unwrap(),
+            Some((addr("1.2.3.4:1111"), addr("5.6.7.8:443")))
+        );
+        assert_eq!(rest, b"\x16\x03");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (re, _) = read(b"PROXY TCP6 2001:db8::1 ::1 1111 443\r\n").await;
+        assert_eq!(
+            re.unwrap(),
+            Some((addr("[2001:db8::1]:1111"), addr("[::1]:443")))
+        );
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (re, rest) = read(b"PROXY UNKNOWN\r\nrest").await;
+        assert_eq!(re.unwrap(), None);
+        assert_eq!(rest, b"rest");
+        let (re, _) = read(b"PROXY UNKNOWN 1.2.3.4 5.6.7.8 1 2\r\n").await;
+        assert_eq!(re.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_invalid() {
+        for data in [
+            &b"PROXY TCP4 ::1 ::1 1 2\r\n"[..],
+            b"PROXY TCP6 1.2.3.4 5.6.7.8 1 2\r\n",
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 1 70000\r\n",
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 1\r\n",
+            b"PROXY UDP4 1.2.3.4 5.6.7.8 1 2\r\n",
+            b"PROXY TCP4 1.2.3.4",
+            b"PROXY",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(read(data).await.0.is_err(), "{:?}", data);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_oversized() {
+        let mut data = b"PROXY TCP6 ".to_vec();
+        data.resize(MAX_V1_LENGTH + 10, b'1');
+        data.extend_from_slice(b"\r\n");
+        let (re, rest) = read(&data).await;
+        assert!(re.is_err());
+        // 超过最大长度后不再继续读取
+        assert_eq!(rest.len(), data.len() - MAX_V1_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4_and_tcp6() {
+        for (src, dst) in [
+            (addr("1.2.3.4:1111"), addr("5.6.7.8:443")),
+            (addr("[2001:db8::1]:1111"), addr("[::1]:443")),
+        ] {
+            let mut data = ProxyVersion::V2.header(Some((src, dst)));
+            data.extend_from_slice(b"rest");
+            let (re, rest) = read(&data).await;
+            assert_eq!(re.unwrap(), Some((src, dst)));
+            assert_eq!(rest, b"rest");
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let mut data = ProxyVersion::V2.header(None);
+        data.extend_from_slice(b"rest");
+        let (re, rest) = read(&data).await;
+        assert_eq!(re.unwrap(), None);
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn v2_skips_tlv() {
+        let mut data = SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 16]);
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0, 1, 0, 2]);
+        data.extend_from_slice(&[0x04, 0, 1, 0xff]);
+        data.extend_from_slice(b"rest");
+        let (re, rest) = read(&data).await;
+        assert_eq!(re.unwrap(), Some((addr("1.2.3.4:1"), addr("5.6.7.8:2"))));
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn v2_unspec_and_udp() {
+        for family in [0x00, 0x12] {
+            let mut data = SIGNATURE.to_vec();
+            data.extend_from_slice(&[0x21, family, 0, 12]);
+            data.extend_from_slice(&[0; 12]);
+            assert_eq!(read(&data).await.0.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        let header = |version_command: u8, family: u8, len: u16, body: &[u8]| {
+            let mut data = SIGNATURE.to_vec();
+            data.extend_from_slice(&[version_command, family]);
+            data.extend_from_slice(&len.to_be_bytes());
+            data.extend_from_slice(body);
+            data
+        };
+        for data in [
+            // 版本不是 2
+            header(0x11, 0x11, 12, &[0; 12]),
+            // 未知命令
+            header(0x22, 0x11, 12, &[0; 12]),
+            // 地址长度不够
+            header(0x21, 0x11, 8, &[0; 8]),
+            header(0x21, 0x21, 12, &[0; 12]),
+            // 数据不完整
+            header(0x21, 0x11, 12, &[0; 6]),
+            SIGNATURE[..10].to_vec(),
+        ] {
+            assert!(read(&data).await.0.is_err(), "{:?}", data);
+        }
+        let mut data = SIGNATURE.to_vec();
+        data[9] = b'X';
+        data.extend_from_slice(&[0x21, 0x11, 0, 0]);
+        assert!(read(&data).await.0.is_err());
+    }
+
+    #[test]
+    fn v1_header() {
+        let header = ProxyVersion::V1.header(Some((addr("1.2.3.4:1111"), addr("5.6.7.8:443"))));
+        assert_eq!(header, b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 443\r\n");
+        assert_eq!(ProxyVersion::V1.header(None), b"PROXY UNKNOWN\r\n");
+        // 地址族不同时转换为 IPv6
+        let header = ProxyVersion::V1.header(Some((addr("1.2.3.4:1111"), addr("[::1]:443"))));
+        assert_eq!(header, b"PROXY TCP6 ::ffff:1.2.3.4 ::1 1111 443\r\n");
+    }
+}
@@ -1,25 +1,48 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::io;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::Instant;
 
+use chrono::Local;
 use log::{debug, error, info, warn};
-use md5::{Digest, Md5};
-use rand::random;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use structopt::StructOpt;
-use tokio::io::{copy_bidirectional, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::time::{sleep, Duration};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, unbounded_channel};
+use tokio::time::{interval, sleep, sleep_until, timeout, Duration};
 use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
-use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::rustls::{Certificate, RootCertStore, ServerConfig};
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
 
-use crate::http::{parse_domain, BAD_GATEWAY, GATEWAY_TIMEOUT};
-use crate::protocol::{Protocol, Receiver, Request};
-use crate::shared::Shared;
-use crate::util::{init_logger, load_certs, load_key};
+use crate::access_log::{AccessLog, AccessLogFormat, Entry, Outcome};
+use crate::acl::Acl;
+use crate::admin;
+use crate::auth::{Auth, AuthOption};
+use crate::cidr::{self, Cidr};
+use crate::error_page::ErrorPages;
+use crate::http::{
+    copy_http, parse_domain, set_forwarded, Status, BAD_GATEWAY, FORBIDDEN, FOUND, GATEWAY_TIMEOUT,
+    NOT_FOUND, SERVICE_UNAVAILABLE, TOO_MANY_REQUESTS, UNAUTHORIZED,
+};
+use crate::limit::{Limits, RateLimit};
+use crate::metrics::{self, Metered};
+use crate::protocol::{Protocol, Receiver, Request, Target, MAX_DATAGRAM_SIZE};
+use crate::proxy;
+use crate::shared::{Conn, ConnChannel, Lookup, Rejection, Rules, Session, Shared};
+use crate::timeout::{copy_bidirectional, domain_secs, Active, DomainSecs, LastActive};
+use crate::util::{cert_subject, init_logger, load_certs, load_key};
 use crate::WithContext;
 
 #[derive(Debug, StructOpt)]
@@ -47,17 +70,232 @@ struct Opt {
     /// 服务端证书
     #[structopt(long)]
     server_cert: String,
+
+    /// TCP 转发端口范围，格式为 "起始端口-结束端口"，不设置则不支持 TCP 转发
+    #[structopt(long)]
+    tcp_port_range: Option<PortRange>,
+
+    /// UDP 转发端口范围，格式为 "起始端口-结束端口"，不设置则不支持 UDP 转发
+    #[structopt(long)]
+    udp_port_range: Option<PortRange>,
+
+    /// UDP 会话空闲超时时间（秒），必须大于 0
+    #[structopt(long, default_value = "60")]
+    udp_session_timeout: u64,
+
+    /// Prometheus 指标绑定地址，格式为 "ip:端口"，不设置则不提供指标
+    #[structopt(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// 访问日志文件，"-" 表示输出到标准输出，不设置则不记录访问日志
+    #[structopt(long)]
+    access_log: Option<String>,
+
+    /// 访问日志格式，json 或 combined
+    #[structopt(long, default_value = "json")]
+    access_log_format: AccessLogFormat,
+
+    /// 管理接口绑定地址，格式为 "ip:端口"，不设置则不提供管理接口
+    #[structopt(long, requires = "admin-token")]
+    admin_addr: Option<SocketAddr>,
+
+    /// 管理接口的访问 token，请求需要带 "Authorization: Bearer <token>" 头
+    #[structopt(long)]
+    admin_token: Option<String>,
+
+    /// 本地管理 unix 域 socket 路径，通过该 socket 的请求不需要 token，由文件权限控制访问
+    #[structopt(long)]
+    admin_socket: Option<PathBuf>,
+
+    /// 收到 SIGTERM 后等待正在转发的连接结束的最长时间（秒），0 表示立即退出
+    #[structopt(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// 信任的代理地址段，来自这些地址的 http 连接需先发送 PROXY protocol v1 或 v2 头，其中的访问者地址用于日志、转发头等。格式如 "10.0.0.0/8"，可指定多个
+    #[structopt(long)]
+    http_proxy_protocol: Vec<Cidr>,
+
+    /// 信任的代理地址段，来自这些地址的客户端连接需先发送 PROXY protocol v1 或 v2 头，格式同 --http-proxy-protocol
+    #[structopt(long)]
+    client_proxy_protocol: Vec<Cidr>,
+
+    /// 域名访问认证，格式为 "域名:basic:htpasswd 文件"（只支持 bcrypt 哈希）或 "域名:bearer:token"，访问者没有提供正确的 Authorization 头时返回 401。优先于客户端注册时要求的认证，可指定多个，重新加载配置时重新读取
+    #[structopt(long)]
+    auth: Vec<AuthOption>,
+
+    /// 访问者地址的访问控制文件，每行格式为 "[域名] allow|deny 地址段 [地址段...]"，不带域名的行对所有域名生效。匹配任一 deny 规则时返回 403；域名有 allow 规则时需匹配其中之一，否则有全局 allow 规则时需匹配全局规则。重新加载配置时重新读取
+    #[structopt(long)]
+    acl: Option<String>,
+
+    /// 错误页面目录，文件名为 "状态码.html" 或 "状态码.json"，以域名命名的子目录中的页面只用于该域名。根据访问者的 Accept 头选择 HTML 或 JSON，页面中的 {domain}、{request_id}、{status}、{reason} 会被替换。重新加载配置时重新读取
+    #[structopt(long)]
+    error_pages: Option<String>,
+
+    /// 已知域名，格式为 "域名" 或 "域名:跳转地址"。客户端未连接时返回 503 和离线页面（错误页面目录中的 offline.html 或 offline.json，没有时使用 503 页面），或者 302 跳转到指定地址。其他没有客户端的域名返回 404，可指定多个
+    #[structopt(long)]
+    known_domain: Vec<KnownDomain>,
+
+    /// 客户端断开后保留域名的宽限期（秒），格式为 "[域名:]秒数"，不带域名时对所有域名生效。宽限期内只有证书 subject 相同的客户端能注册该域名，新的访问者等待客户端重新注册，宽限期结束时返回 504。默认为 0，可指定多个
+    #[structopt(long)]
+    grace_period: Vec<DomainSecs>,
+
+    /// 等待访问者发送请求头的超时（秒），超时后关闭连接，必须大于 0。此时还不知道域名，只能全局配置
+    #[structopt(long, default_value = "30")]
+    parse_timeout: u64,
+
+    /// 发出转发请求后等待客户端建立转发连接的超时（秒），格式为 "[域名:]秒数"，TCP 转发的域名为 "tcp:端口"。超时返回 504，必须大于 0，默认为 15，可指定多个
+    #[structopt(long)]
+    response_timeout: Vec<DomainSecs>,
+
+    /// 转发连接的空闲超时（秒），两个方向都没有数据超过该时间时关闭连接，格式同 --response-timeout。0 表示不限制，如 "a.com:0" 取消 a.com 的空闲超时。默认为 0，可指定多个
+    #[structopt(long)]
+    idle_timeout: Vec<DomainSecs>,
+
+    /// 客户端超过该时间（秒）没有发送 Ping 时断开该客户端，必须大于 0
+    #[structopt(long, default_value = "300")]
+    client_idle_timeout: u64,
+
+    /// 每个域名每秒允许的新连接数（令牌桶），格式为 "每秒数量[/突发数量]"，如 "10/20"，突发数量默认等于每秒数量。超过时返回 429
+    #[structopt(long)]
+    domain_rate_limit: Option<RateLimit>,
+
+    /// 每个域名同时转发的最大连接数，超过时返回 429
+    #[structopt(long)]
+    domain_max_connections: Option<usize>,
+
+    /// 每个访问者地址每秒允许的新连接数，格式同 --domain-rate-limit
+    #[structopt(long)]
+    ip_rate_limit: Option<RateLimit>,
+
+    /// 每个访问者地址同时转发的最大连接数，超过时返回 429
+    #[structopt(long)]
+    ip_max_connections: Option<usize>,
+
+    /// 等待客户端应答的转发连接总数上限，超过时新的访问者收到 503
+    #[structopt(long, default_value = "4096")]
+    max_pending: usize,
+
+    /// 每个客户端等待应答的转发连接数上限，超过时该客户端新的访问者收到 503
+    #[structopt(long, default_value = "256")]
+    max_pending_per_client: usize,
+
+    /// 向转发的 HTTP 请求添加 X-Forwarded-For、X-Forwarded-Proto、X-Forwarded-Host 和 Forwarded 头，并删除访问者发来的同名头
+    #[structopt(long)]
+    forwarded_headers: bool,
+}
+
+// 每个 UDP 端口待发送的数据报队列长度
+const UDP_QUEUE_SIZE: usize = 1024;
+
+// 等待 PROXY 头的最长时间
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 客户端发送 GoingAway 后检查其转发请求是否都已处理完的间隔
+const LEAVING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// 等待被取代的旧会话断开的最长时间, 及检查的间隔
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLACE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// TCP 转发端口范围
+#[derive(Debug, Copy, Clone)]
+struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+#[derive(Debug)]
+struct InvalidPortRange;
+
+impl Display for InvalidPortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("wrong format", f)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = InvalidPortRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or(InvalidPortRange)?;
+        let start = start.trim().parse().map_err(|_| InvalidPortRange)?;
+        let end = end.trim().parse().map_err(|_| InvalidPortRange)?;
+        if start == 0 || start > end {
+            return Err(InvalidPortRange);
+        }
+        Ok(Self { start, end })
+    }
+}
+
+// 已知域名, 客户端未连接时返回离线页面或跳转
+#[derive(Debug)]
+struct KnownDomain {
+    domain: String,
+    redirect: Option<String>, // 跳转地址
+}
+
+#[derive(Debug)]
+struct InvalidKnownDomain;
+
+impl Display for InvalidKnownDomain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("wrong format", f)
+    }
+}
+
+impl FromStr for KnownDomain {
+    type Err = InvalidKnownDomain;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (domain, redirect) = match s.split_once(':') {
+            Some((domain, redirect)) if !redirect.is_empty() => {
+                (domain, Some(redirect.to_string()))
+            }
+            Some(_) => return Err(InvalidKnownDomain),
+            None => (s, None),
+        };
+        if domain.is_empty() {
+            return Err(InvalidKnownDomain);
+        }
+        Ok(Self {
+            domain: domain.to_string(),
+            redirect,
+        })
+    }
+}
+
+// 转发目标的超时设置
+#[derive(Debug, Copy, Clone)]
+struct ForwardTimeouts {
+    response: Duration, // 等待客户端建立转发连接
+    idle: Duration,     // 转发连接空闲, 0 表示不限制
+}
+
+impl ForwardTimeouts {
+    // name 为域名或 "tcp:端口"
+    fn new(opt: &Opt, name: &str) -> Self {
+        Self {
+            response: domain_secs(&opt.response_timeout, name, 15),
+            idle: domain_secs(&opt.idle_timeout, name, 0),
+        }
+    }
 }
 
 pub async fn run() -> crate::Result<()> {
     init_logger();
-    let opt: Opt = Opt::from_args();
+    let opt = Arc::new(validate_opt());
 
-    let http_acceptor = create_http_acceptor(&opt.http_key, &opt.http_cert)?;
+    let mut http_acceptor = create_http_acceptor(&opt.http_key, &opt.http_cert)?;
     let http_listener = TcpListener::bind(opt.http_addr)
         .await
         .map_err(err!("cannot bind {}", opt.http_addr))?;
-    let client_acceptor = create_client_acceptor(&opt.server_key, &opt.server_cert)?;
+    let mut client_acceptor = create_client_acceptor(&opt.server_key, &opt.server_cert)?;
     let client_listener = TcpListener::bind(opt.addr)
         .await
         .map_err(err!("cannot bind {}", opt.addr))?;
@@ -69,40 +307,185 @@ pub async fn run() -> crate::Result<()> {
 
     let mut sig_int = signal(SignalKind::interrupt()).map_err(err!())?;
     let mut sig_term = signal(SignalKind::terminate()).map_err(err!())?;
-    let shared = Shared::new();
+    let mut sig_hup = signal(SignalKind::hangup()).map_err(err!())?;
+    let access_log = match &opt.access_log {
+        Some(path) => AccessLog::open(path, opt.access_log_format).await?,
+        None => AccessLog::disabled(),
+    };
+    let (reload_tx, mut reload_rx) = unbounded_channel();
+    let limits = Limits::new(
+        opt.domain_rate_limit,
+        opt.domain_max_connections,
+        opt.ip_rate_limit,
+        opt.ip_max_connections,
+    );
+    let conn = ConnChannel::new(opt.max_pending, opt.max_pending_per_client);
+    let shared = Shared::new(access_log, limits, conn, reload_tx);
+    shared.rules.set(load_rules(&opt)?);
+    tokio::spawn(cleanup_limits(shared.clone()));
+    if let Some(addr) = opt.metrics_addr {
+        let shared = shared.clone();
+        metrics::serve(addr, move || shared.render_metrics()).await?;
+    }
+    if let (Some(addr), Some(token)) = (opt.admin_addr, &opt.admin_token) {
+        admin::serve(addr, token.clone(), shared.clone()).await?;
+    }
+    if let Some(path) = &opt.admin_socket {
+        admin::serve_unix(path, shared.clone()).await?;
+    }
     loop {
         tokio::select! {
             accept = client_listener.accept() => {
-                handle_client_accept(accept, &client_acceptor, &shared).await;
+                handle_client_accept(accept, &client_acceptor, &opt, &shared).await;
             }
             accept = http_listener.accept() => {
-                handle_http_accept(accept, &http_acceptor, &shared).await;
+                handle_http_accept(accept, &http_acceptor, &opt, &shared).await;
+            }
+            _ = sig_hup.recv() => {
+                info!("catch SIGHUP, reloading");
+                if let Err(e) = reload(&opt, &mut http_acceptor, &mut client_acceptor, &shared) {
+                    error!("{}", e);
+                }
+            }
+            Some(reply) = reload_rx.recv() => {
+                let re = reload(&opt, &mut http_acceptor, &mut client_acceptor, &shared);
+                let _ = reply.send(re.map_err(|e| e.to_string()));
             }
             _ = sig_int.recv() => {
                 info!("catch SIGINT, exiting");
                 break;
             }
             _ = sig_term.recv() => {
-                info!("catch SIGTERM, exiting");
+                info!("catch SIGTERM, shutting down");
+                drop(http_listener);
+                shutdown(client_listener, &client_acceptor, &opt, &shared, &mut sig_int).await;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 定期删除空闲的限制计数
+async fn cleanup_limits(shared: Shared) {
+    let mut tick = interval(Duration::from_secs(60));
+    loop {
+        tick.tick().await;
+        shared.limits.cleanup();
+    }
+}
+
+// 不再接受访问者的连接, 通知客户端重新连接, 等待正在转发的连接结束或超时.
+// 期间仍接受客户端的转发连接, 以完成已发出的转发请求
+async fn shutdown(
+    client_listener: TcpListener,
+    client_acceptor: &TlsAcceptor,
+    opt: &Arc<Opt>,
+    shared: &Shared,
+    sig_int: &mut Signal,
+) {
+    shared.client.going_away();
+    let deadline = sleep(Duration::from_secs(opt.shutdown_timeout));
+    tokio::pin!(deadline);
+    let mut tick = interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            accept = client_listener.accept() => {
+                handle_client_accept(accept, client_acceptor, opt, shared).await;
+            }
+            _ = tick.tick() => {
+                let n = shared.metrics.connections.get();
+                if n == 0 {
+                    info!("all connections finished, exiting");
+                    break;
+                }
+            }
+            _ = &mut deadline => {
+                info!(
+                    "{} connections still active after {} seconds, exiting",
+                    shared.metrics.connections.get(),
+                    opt.shutdown_timeout
+                );
+                break;
+            }
+            _ = sig_int.recv() => {
+                info!("catch SIGINT, exiting");
                 break;
             }
         }
     }
+}
+
+// 重新加载证书和访问规则, 失败时保留原来的配置
+fn reload(
+    opt: &Opt,
+    http_acceptor: &mut TlsAcceptor,
+    client_acceptor: &mut TlsAcceptor,
+    shared: &Shared,
+) -> crate::Result<()> {
+    let http = create_http_acceptor(&opt.http_key, &opt.http_cert)?;
+    let client = create_client_acceptor(&opt.server_key, &opt.server_cert)?;
+    let rules = load_rules(opt)?;
+    *http_acceptor = http;
+    *client_acceptor = client;
+    shared.rules.set(rules);
+    info!("reloaded");
     Ok(())
 }
 
+fn load_rules(opt: &Opt) -> crate::Result<Rules> {
+    let mut auth = HashMap::with_capacity(opt.auth.len());
+    for v in &opt.auth {
+        auth.insert(v.domain.clone(), Arc::new(v.load()?));
+    }
+    let acl = match &opt.acl {
+        Some(path) => Acl::load(path)?,
+        None => Acl::default(),
+    };
+    let error_pages = match &opt.error_pages {
+        Some(dir) => ErrorPages::load(dir)?,
+        None => ErrorPages::default(),
+    };
+    Ok(Rules {
+        auth,
+        acl,
+        error_pages,
+    })
+}
+
+fn validate_opt() -> Opt {
+    let opt: Opt = Opt::from_args();
+    if opt.parse_timeout == 0
+        || opt.client_idle_timeout == 0
+        || opt.response_timeout.iter().any(|v| v.secs() == 0)
+    {
+        eprintln!(
+            "--parse-timeout, --client-idle-timeout and --response-timeout must be greater than 0"
+        );
+        exit(1);
+    }
+    // tokio 的 interval 不接受 0
+    if opt.udp_session_timeout == 0 {
+        eprintln!("--udp-session-timeout must be greater than 0");
+        exit(1);
+    }
+    opt
+}
+
 async fn handle_client_accept(
     accept: io::Result<(TcpStream, SocketAddr)>,
     acceptor: &TlsAcceptor,
+    opt: &Arc<Opt>,
     shared: &Shared,
 ) {
     match accept {
         Ok((stream, addr)) => {
             debug!("client connection from {}", addr);
             let acceptor = acceptor.clone();
+            let opt = opt.clone();
             let shared = shared.clone();
             tokio::spawn(async move {
-                if let Err(e) = handle_client(stream, addr, acceptor, shared).await {
+                if let Err(e) = handle_client(stream, addr, acceptor, opt, shared).await {
                     error!("{}", e);
                 }
             });
@@ -112,40 +495,98 @@ async fn handle_client_accept(
 }
 
 async fn handle_client(
-    stream: TcpStream,
+    mut stream: TcpStream,
     addr: SocketAddr,
     acceptor: TlsAcceptor,
+    opt: Arc<Opt>,
     shared: Shared,
 ) -> crate::Result<()> {
-    let mut stream = acceptor
-        .accept(stream)
-        .await
-        .map_err(err!("Tls accept error"))
-        .ctx("peer", addr)?;
+    let (addr, _) = accept_proxy(&mut stream, addr, &opt.client_proxy_protocol).await?;
+    let mut stream = match acceptor.accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            shared.metrics.tls_failures.get(&["client"]).inc();
+            return Err(e).map_err(err!("Tls accept error")).ctx("peer", addr);
+        }
+    };
 
+    let cert = peer_cert(&stream);
+    let subject = cert_subject(&cert);
     let mut receiver = Receiver::new();
     let msg = receiver.recv(&mut stream).await?;
     match msg {
-        Some(Protocol::Register { domains })
-            if !domains.is_empty() && !shared.client.exists(&domains) =>
+        Some(Protocol::Register { .. }) if shared.client.is_going_away() => {
+            Protocol::GoingAway.send(&mut stream).await?;
+            let _ = stream.shutdown().await;
+        }
+        Some(Protocol::Register {
+            domains,
+            ports,
+            udp_ports,
+            auth,
+        }) if (!domains.is_empty() || !ports.is_empty() || !udp_ports.is_empty())
+            && !shared.client.exists(&domains, &subject) =>
         {
-            Protocol::Ok.send(&mut stream).await.map_err(err!())?;
-            let re = handle_register(stream, addr, &domains, &shared).await;
-            shared.client.remove(&domains);
+            replace_stale(&shared, &domains, &subject, addr).await;
+            let ip = opt.http_addr.ip();
+            let bind = async {
+                let listeners = bind_ports(&ports, opt.tcp_port_range, |port| {
+                    TcpListener::bind((ip, port))
+                })
+                .await?;
+                let sockets = bind_ports(&udp_ports, opt.udp_port_range, |port| {
+                    UdpSocket::bind((ip, port))
+                })
+                .await?;
+                Ok::<_, crate::Error>((listeners, sockets))
+            };
+            let (listeners, sockets) = match bind.await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{}", e);
+                    Protocol::Error.send(&mut stream).await?;
+                    let _ = stream.shutdown().await;
+                    return Ok(());
+                }
+            };
+            let mut ports = Vec::with_capacity(listeners.len());
+            for v in &listeners {
+                ports.push(v.local_addr().map_err(err!())?.port());
+            }
+            let mut udp_ports = Vec::with_capacity(sockets.len());
+            for v in &sockets {
+                udp_ports.push(v.local_addr().map_err(err!())?.port());
+            }
+            Protocol::Ok {
+                ports: ports.clone(),
+                udp_ports: udp_ports.clone(),
+            }
+            .send(&mut stream)
+            .await
+            .map_err(err!())?;
+
+            let (session, rx) = shared
+                .client
+                .add(addr, cert, domains, ports, udp_ports, auth);
+            let re = handle_register(stream, &session, rx, listeners, sockets, &opt, &shared).await;
+            shared
+                .client
+                .remove(&session, |domain| domain_secs(&opt.grace_period, domain, 0));
+            shared.conn.remove_client(session.id);
             re?
         }
         Some(Protocol::Register { .. }) => {
             Protocol::Error.send(&mut stream).await?;
             let _ = stream.shutdown().await;
         }
-        Some(Protocol::Response { key }) => match shared.conn.remove(&key) {
-            Some(sender) => match sender.send(stream) {
-                Ok(()) => {}
-                Err(mut stream) => {
+        Some(Protocol::Response { key }) => match shared.conn.claim(&key, &cert.0) {
+            Some(sender) => {
+                if let Err(Ok(mut stream)) = sender.send(Ok(stream)) {
                     let _ = stream.shutdown().await;
                 }
-            },
+            }
             None => {
+                warn!("invalid or expired forward key from {}", addr);
                 let _ = stream.shutdown().await;
             }
         },
@@ -154,58 +595,371 @@ async fn handle_client(
     Ok(())
 }
 
+// 断开证书 subject 相同, 处理相同域名的旧会话, 等待其释放监听的端口
+async fn replace_stale(shared: &Shared, domains: &[String], subject: &str, addr: SocketAddr) {
+    for session in shared.client.stale(domains, subject) {
+        info!("client {} replaces {}", addr, session.addr);
+        session.kick.notify_one();
+        let deadline = Instant::now() + REPLACE_TIMEOUT;
+        while shared.client.session(session.id).is_some() && Instant::now() < deadline {
+            sleep(REPLACE_CHECK_INTERVAL).await;
+        }
+    }
+}
+
 async fn handle_register(
     mut stream: TlsStream<TcpStream>,
-    addr: SocketAddr,
-    domains: &[String],
+    client: &Arc<Session>,
+    mut tx: mpsc::Receiver<Protocol>,
+    listeners: Vec<TcpListener>,
+    sockets: Vec<UdpSocket>,
+    opt: &Opt,
     shared: &Shared,
 ) -> crate::Result<()> {
-    let mut tx = shared.client.add(domains.to_vec());
+    let _client_guard = shared.metrics.clients.track();
+    let addr = client.addr;
+    let mut tcp_tasks = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        let port = listener.local_addr().map_err(err!())?.port();
+        let timeouts = ForwardTimeouts::new(opt, &Target::Port(port).to_string());
+        let client = client.clone();
+        let shared = shared.clone();
+        tcp_tasks.push(tokio::spawn(handle_tcp_listener(
+            listener, timeouts, client, shared,
+        )));
+    }
+    let mut udp_tasks = Vec::with_capacity(sockets.len());
+    // key 为 UDP 端口, value 用来把客户端发来的数据报交给对应的 UDP socket
+    let mut udp = HashMap::with_capacity(sockets.len());
+    let timeout = Duration::from_secs(opt.udp_session_timeout);
+    for socket in sockets {
+        let port = socket.local_addr().map_err(err!())?.port();
+        let (udp_tx, udp_rx) = mpsc::channel(UDP_QUEUE_SIZE);
+        udp.insert(port, udp_tx);
+        let client = client.tx.clone();
+        udp_tasks.push(tokio::spawn(handle_udp_socket(
+            socket, port, client, udp_rx, timeout,
+        )));
+    }
+
     let mut receiver = Receiver::new();
+    let idle = Duration::from_secs(opt.client_idle_timeout);
     let mut ping_at = Instant::now();
+    let mut leaving = false;
     loop {
         tokio::select! {
             msg = receiver.recv(&mut stream) => {
                 match msg? {
                     Some(Protocol::Ping) => {
                         ping_at = Instant::now();
+                        *client.last_ping.lock().unwrap() = Some(Local::now());
                         Protocol::Pong.send(&mut stream).await.map_err(err!())?;
                     }
+                    Some(Protocol::Reject { key, reason }) => {
+                        warn!("client {} rejected forward: {}", addr, reason);
+                        if let Some(sender) = shared.conn.claim(&key, &client.cert) {
+                            let _ = sender.send(Err(Rejection::Unreachable(reason)));
+                        }
+                    }
+                    Some(Protocol::NotFound { key }) => {
+                        if let Some(sender) = shared.conn.claim(&key, &client.cert) {
+                            let _ = sender.send(Err(Rejection::NotFound));
+                        }
+                    }
+                    Some(Protocol::UdpData { port, session, data }) => {
+                        // 队列满时丢弃数据报
+                        if let Some(udp_tx) = udp.get(&port) {
+                            let _ = udp_tx.try_send((session, data));
+                        }
+                    }
+                    // 不再转发新连接, 已发出的转发请求被领取或过期后断开
+                    Some(Protocol::GoingAway) => {
+                        info!("client {} going away", addr);
+                        leaving = true;
+                        shared.client.retire(client, |domain| domain_secs(&opt.grace_period, domain, 0));
+                        for task in tcp_tasks.drain(..) {
+                            task.abort();
+                        }
+                    }
                     Some(msg) => warn!("unexpected msg {:?} from {}", msg, addr),
                     None => break,
                 }
             }
             msg = tx.recv() => {
-                match msg {
-                    Some(req) => Protocol::Request(req).send(&mut stream).await?,
-                    None => {}
+                if let Some(msg) = msg {
+                    msg.send(&mut stream).await?;
                 }
             }
-            _ = sleep(Duration::from_secs(60)) => {
-                if ping_at.elapsed() > Duration::from_secs(300) {
-                    info!("{} inactive for more than 300 seconds", addr);
+            _ = client.kick.notified() => {
+                info!("client {} kicked", addr);
+                break;
+            }
+            _ = sleep_until((ping_at + idle).into()) => {
+                info!("{} inactive for more than {} seconds", addr, opt.client_idle_timeout);
+                break;
+            }
+            _ = sleep(LEAVING_CHECK_INTERVAL), if leaving => {}
+        }
+        if leaving && shared.conn.count(client.id) == 0 {
+            info!("client {} left", addr);
+            break;
+        }
+    }
+
+    // 等待任务结束, 确保端口已释放
+    for task in tcp_tasks.into_iter().chain(udp_tasks) {
+        task.abort();
+        let _ = task.await;
+    }
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+// 绑定客户端请求的端口, 端口为 0 时从端口范围中分配
+async fn bind_ports<T, F, Fut>(
+    ports: &[u16],
+    range: Option<PortRange>,
+    bind: F,
+) -> crate::Result<Vec<T>>
+where
+    F: Fn(u16) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut bound = Vec::with_capacity(ports.len());
+    if ports.is_empty() {
+        return Ok(bound);
+    }
+
+    let range = range
+        .ok_or_else(|| io::Error::new(ErrorKind::Unsupported, "port forward disabled"))
+        .map_err(err!())?;
+    for &port in ports {
+        let v = if port == 0 {
+            let mut found = None;
+            for port in range.start..=range.end {
+                if let Ok(v) = bind(port).await {
+                    found = Some(v);
                     break;
                 }
             }
+            found
+                .ok_or_else(|| io::Error::new(ErrorKind::AddrInUse, "no port available"))
+                .map_err(err!(
+                    "cannot allocate port in {}-{}",
+                    range.start,
+                    range.end
+                ))?
+        } else if range.contains(port) {
+            bind(port).await.map_err(err!("cannot bind {}", port))?
+        } else {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "port out of range",
+            ))
+            .map_err(err!())
+            .ctx("port", port);
+        };
+        bound.push(v);
+    }
+    Ok(bound)
+}
+
+async fn handle_tcp_listener(
+    listener: TcpListener,
+    timeouts: ForwardTimeouts,
+    client: Arc<Session>,
+    shared: Shared,
+) {
+    let port = match listener.local_addr() {
+        Ok(addr) => addr.port(),
+        Err(err) => {
+            error!("tcp listener error: {}", err);
+            return;
+        }
+    };
+    info!("tcp forward started at {}", port);
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                debug!("tcp connection from {} to port {}", addr, port);
+                let client = client.clone();
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_tcp(stream, addr, port, timeouts, client, shared).await
+                    {
+                        error!("{}", err);
+                    }
+                });
+            }
+            Err(err) => error!("tcp accept error: {}", err),
         }
     }
+}
 
-    let _ = stream.shutdown().await;
+async fn handle_tcp(
+    stream: TcpStream,
+    addr: SocketAddr,
+    port: u16,
+    timeouts: ForwardTimeouts,
+    client: Arc<Session>,
+    shared: Shared,
+) -> crate::Result<()> {
+    let _connection = shared.metrics.connections.track();
+    let target = Target::Port(port);
+    let name = target.to_string();
+    let local = stream.local_addr().map_err(err!())?;
+    let mut entry = Entry::new(addr, local);
+    entry.target = Some(name.clone());
+    entry.client = Some(client.subject.clone());
+
+    let metrics = &shared.metrics;
+    let received = metrics.received_bytes.get(&[&name]);
+    let mut stream = Metered::new(stream, received, metrics.sent_bytes.get(&[&name]));
+    let re = forward_tcp(&mut stream, target, timeouts, &client, &mut entry, &shared).await;
+    (entry.received, entry.sent) = stream.total();
+    shared.access_log.log(&entry);
+    re
+}
+
+async fn forward_tcp(
+    stream: &mut Metered<TcpStream>,
+    target: Target,
+    timeouts: ForwardTimeouts,
+    client: &Session,
+    entry: &mut Entry,
+    shared: &Shared,
+) -> crate::Result<()> {
+    let name = target.to_string();
+    let metrics = &shared.metrics;
+    if shared.client.is_blocked(&name) {
+        warn!("{} is blocked", name);
+        metrics.error(&name, FORBIDDEN);
+        entry.outcome = Outcome::Forbidden;
+        let _ = stream.shutdown().await;
+        return Ok(());
+    }
+    // 端口按 "tcp:端口" 计入域名的限制
+    let _permit = match shared.limits.acquire(&name, entry.visitor.ip()) {
+        Ok(permit) => permit,
+        Err(_) => {
+            warn!("{} too many connections from {}", name, entry.visitor);
+            metrics.error(&name, TOO_MANY_REQUESTS);
+            entry.outcome = Outcome::TooManyRequests;
+            let _ = stream.shutdown().await;
+            return Ok(());
+        }
+    };
+
+    let addrs = (entry.visitor, entry.local);
+    match request_conn(client, target.clone(), addrs, timeouts.response, shared).await? {
+        Some(Ok(mut conn)) => {
+            let _active = metrics.active.get(&[&name]).track();
+            debug!("forward {} start", target);
+            let re = copy_bidirectional(stream, &mut conn, timeouts.idle).await;
+            if let Err(e) = &re {
+                entry.outcome = copy_outcome(e);
+            }
+            re.map_err(err!("forward {}", target))?;
+            debug!("forward {} end", target);
+        }
+        Some(Err(rejection)) => {
+            log_rejection(&name, &rejection);
+            let (status, outcome) = rejection_status(&rejection);
+            metrics.error(&name, status);
+            entry.outcome = outcome;
+            let _ = stream.shutdown().await;
+        }
+        None => {
+            error!("{} timeout", target);
+            metrics.error(&name, GATEWAY_TIMEOUT);
+            entry.outcome = Outcome::GatewayTimeout;
+            let _ = stream.shutdown().await;
+        }
+    }
     Ok(())
 }
 
+// 转发 UDP 端口上的数据报, 每个对端地址对应一个会话, 空闲超过 timeout 的会话被移除
+async fn handle_udp_socket(
+    socket: UdpSocket,
+    port: u16,
+    client: mpsc::Sender<Protocol>,
+    mut rx: mpsc::Receiver<(u32, Vec<u8>)>,
+    timeout: Duration,
+) {
+    info!("udp forward started at {}", port);
+    let mut sessions: HashMap<SocketAddr, u32> = HashMap::new();
+    let mut peers: HashMap<u32, (SocketAddr, Instant)> = HashMap::new();
+    let mut next_session = 0u32;
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut tick = interval(timeout);
+    loop {
+        tokio::select! {
+            recv = socket.recv_from(&mut buf) => {
+                let (n, addr) = match recv {
+                    Ok(v) => v,
+                    Err(err) => {
+                        error!("udp recv error: {}", err);
+                        continue;
+                    }
+                };
+                let session = *sessions.entry(addr).or_insert_with(|| {
+                    next_session = next_session.wrapping_add(1);
+                    debug!("udp session {} from {} to port {}", next_session, addr, port);
+                    next_session
+                });
+                peers.insert(session, (addr, Instant::now()));
+                let data = buf[..n].to_vec();
+                // 客户端消息队列满时丢弃数据报
+                match client.try_send(Protocol::UdpData { port, session, data }) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => debug!("udp port {} queue full, drop", port),
+                    Err(TrySendError::Closed(_)) => break,
+                }
+            }
+            msg = rx.recv() => {
+                let (session, data) = match msg {
+                    Some(v) => v,
+                    None => break,
+                };
+                if let Some((addr, active_at)) = peers.get_mut(&session) {
+                    *active_at = Instant::now();
+                    if let Err(err) = socket.send_to(&data, *addr).await {
+                        error!("udp send to {} error: {}", addr, err);
+                    }
+                }
+            }
+            _ = tick.tick() => {
+                let expired: Vec<_> = peers
+                    .iter()
+                    .filter(|(_, (_, active_at))| active_at.elapsed() > timeout)
+                    .map(|(&session, &(addr, _))| (session, addr))
+                    .collect();
+                for (session, addr) in expired {
+                    debug!("udp session {} from {} expired", session, addr);
+                    peers.remove(&session);
+                    sessions.remove(&addr);
+                    let _ = client.send(Protocol::UdpClose { port, session }).await;
+                }
+            }
+        }
+    }
+}
+
 async fn handle_http_accept(
     accept: io::Result<(TcpStream, SocketAddr)>,
     acceptor: &TlsAcceptor,
+    opt: &Arc<Opt>,
     shared: &Shared,
 ) {
     match accept {
         Ok((stream, addr)) => {
             debug!("http connection from {}", addr);
             let acceptor = acceptor.clone();
+            let opt = opt.clone();
             let shared = shared.clone();
             tokio::spawn(async move {
-                match handle_http(stream, addr, acceptor, shared).await {
+                match handle_http(stream, addr, acceptor, opt, shared).await {
                     Ok(()) => {}
                     Err(err) => error!("{}", err),
                 }
@@ -216,65 +970,412 @@ async fn handle_http_accept(
 }
 
 async fn handle_http(
-    stream: TcpStream,
+    mut stream: TcpStream,
     addr: SocketAddr,
     acceptor: TlsAcceptor,
+    opt: Arc<Opt>,
     shared: Shared,
 ) -> crate::Result<()> {
-    let mut stream = acceptor
-        .accept(stream)
-        .await
-        .map_err(err!("Tls accept error"))
-        .ctx("peer", addr)?;
+    let _connection = shared.metrics.connections.track();
+    let (addr, local) = accept_proxy(&mut stream, addr, &opt.http_proxy_protocol).await?;
+    let mut stream = match acceptor.accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            shared.metrics.tls_failures.get(&["http"]).inc();
+            return Err(e).map_err(err!("Tls accept error")).ctx("peer", addr);
+        }
+    };
 
-    tokio::select! {
-        result = parse_domain(&mut stream) => {
-            let result = result?;
-            if let Some(client) = shared.client.get(&result.domain) {
-                let key = make_key(&result.domain);
-                let req = Request::new(key.clone(), result.domain.clone());
-                client.send(req).map_err(err!())?;
-                let receiver = shared.conn.add(key.clone());
+    let request_id = make_request_id();
+    let mut entry = Entry::new(addr, local);
+    entry.request_id = Some(request_id.clone());
+    entry.sni = stream.get_ref().1.sni_hostname().map(|v| v.to_string());
+    let re = tokio::select! {
+        result = parse_domain(&mut stream) => match result {
+            Err(e) => {
+                entry.outcome = Outcome::Invalid;
+                Err(e)
+            }
+            Ok(result) => {
+                entry.target = Some(result.domain.clone());
+                entry.host = Some(result.domain.clone());
+                if let Some((method, path, version)) = result.request_line() {
+                    entry.method = Some(method.to_string());
+                    entry.path = Some(path.to_string());
+                    entry.version = Some(version.to_string());
+                }
+                entry.referer = result.header("referer").map(|v| v.to_string());
+                entry.user_agent = result.header("user-agent").map(|v| v.to_string());
+                entry.received = result.buf.len() as u64;
+                // 通过封禁和访问控制检查后才获取许可, 许可在转发结束前一直持有
+                let mut permit = None;
+                let rules = shared.rules.get();
+                let reply = Reply {
+                    domain: &result.domain,
+                    request_id: &request_id,
+                    accept: result.header("accept"),
+                    rules: &rules,
+                };
+                // 指标的域名标签只用已注册、已知或被禁止的域名, 其他 Host 统一记为 "-",
+                // 避免访问者用任意 Host 增加指标数量
+                let label = if shared.client.contains(&result.domain)
+                    || shared.client.is_blocked(&result.domain)
+                    || opt.known_domain.iter().any(|v| v.domain == result.domain)
+                {
+                    result.domain.as_str()
+                } else {
+                    "-"
+                };
+
+                if shared.client.is_blocked(&result.domain) {
+                    warn!("{} is blocked", result.domain);
+                    shared.metrics.error(&result.domain, FORBIDDEN);
+                    entry.outcome = Outcome::Forbidden;
+                    reply.send(&mut stream, FORBIDDEN, &[]).await
+                } else if !rules.acl.allows(&result.domain, addr.ip()) {
+                    warn!("{} denied {}", result.domain, addr);
+                    shared.metrics.error(label, FORBIDDEN);
+                    entry.outcome = Outcome::Forbidden;
+                    reply.send(&mut stream, FORBIDDEN, &[]).await
+                } else if let Err(retry) = permit.insert(shared.limits.acquire(&result.domain, addr.ip())) {
+                    warn!("{} too many requests from {}", result.domain, addr);
+                    shared.metrics.error(label, TOO_MANY_REQUESTS);
+                    entry.outcome = Outcome::TooManyRequests;
+                    let headers = [("retry-after", retry.to_string())];
+                    reply.send(&mut stream, TOO_MANY_REQUESTS, &headers).await
+                } else if shared.client.is_draining(&result.domain) {
+                    warn!("{} is draining", result.domain);
+                    shared.metrics.error(&result.domain, SERVICE_UNAVAILABLE);
+                    entry.outcome = Outcome::Unavailable;
+                    reply.send(&mut stream, SERVICE_UNAVAILABLE, &[]).await
+                } else {
+                    match shared.client.lookup(&result.domain).await {
+                        Lookup::Found(client) => {
+                            entry.client = Some(client.subject.clone());
+                            match find_auth(&rules, &client, &result.domain) {
+                                Some(auth) if !auth.check(result.header("authorization")).await => {
+                                    warn!("{} unauthorized {}", result.domain, addr);
+                                    shared.metrics.error(&result.domain, UNAUTHORIZED);
+                                    entry.outcome = Outcome::Unauthorized;
+                                    let headers = [("www-authenticate", auth.challenge(&result.domain))];
+                                    reply.send(&mut stream, UNAUTHORIZED, &headers).await
+                                }
+                                _ => {
+                                    let domain = result.domain.as_str();
+                                    let metrics = &shared.metrics;
+                                    let received = metrics.received_bytes.get(&[domain]);
+                                    received.add(result.buf.len() as u64);
+                                    let mut stream = Metered::new(stream, received, metrics.sent_bytes.get(&[domain]));
+                                    let re = forward_http(&mut stream, &result.buf, &reply, &client, &mut entry, &opt, &shared).await;
+                                    let (received, sent) = stream.total();
+                                    entry.received += received;
+                                    entry.sent = sent;
+                                    re
+                                }
+                            }
+                        }
+                        Lookup::Expired => {
+                            warn!("{} client did not reconnect in grace period", result.domain);
+                            shared.metrics.error(&result.domain, GATEWAY_TIMEOUT);
+                            entry.outcome = Outcome::GatewayTimeout;
+                            reply.send(&mut stream, GATEWAY_TIMEOUT, &[]).await
+                        }
+                        Lookup::NotFound => match opt.known_domain.iter().find(|v| v.domain == result.domain) {
+                            Some(known) => {
+                                warn!("{} is offline", result.domain);
+                                shared.metrics.offline.get(&[&result.domain]).inc();
+                                match &known.redirect {
+                                    Some(location) => {
+                                        entry.outcome = Outcome::OfflineRedirect;
+                                        let headers = [("location", location.clone())];
+                                        reply.send(&mut stream, FOUND, &headers).await
+                                    }
+                                    None => {
+                                        entry.outcome = Outcome::Offline;
+                                        reply.send_page(&mut stream, "offline", SERVICE_UNAVAILABLE, &[]).await
+                                    }
+                                }
+                            }
+                            None => {
+                                warn!("unknown domain {}", result.domain);
+                                shared.metrics.unknown_domains.inc();
+                                entry.outcome = Outcome::UnknownDomain;
+                                reply.send(&mut stream, NOT_FOUND, &[]).await
+                            }
+                        },
+                    }
+                }
+            }
+        },
+        _ = sleep(Duration::from_secs(opt.parse_timeout)) => {
+            let _ = stream.shutdown().await;
+            shared.metrics.parse_timeouts.inc();
+            entry.outcome = Outcome::Timeout;
+            error!("{} parse domain timeout", addr);
+            Ok(())
+        }
+    };
 
+    shared.access_log.log(&entry);
+    re
+}
+
+// 域名的访问认证, 服务端配置优先于客户端要求
+fn find_auth(rules: &Rules, client: &Session, domain: &str) -> Option<Arc<Auth>> {
+    match rules.auth.get(domain) {
+        Some(auth) => Some(auth.clone()),
+        None => client.auth.get(domain).cloned(),
+    }
+}
+
+async fn forward_http(
+    stream: &mut Metered<TlsStream<TcpStream>>,
+    buf: &[u8],
+    reply: &Reply<'_>,
+    client: &Session,
+    entry: &mut Entry,
+    opt: &Opt,
+    shared: &Shared,
+) -> crate::Result<()> {
+    let domain = reply.domain;
+    let metrics = &shared.metrics;
+    let target = Target::Domain(domain.to_string());
+    let addrs = (entry.visitor, entry.local);
+    let timeouts = ForwardTimeouts::new(opt, domain);
+    match request_conn(client, target, addrs, timeouts.response, shared).await? {
+        Some(Ok(mut conn)) => {
+            let _active = metrics.active.get(&[domain]).track();
+            debug!("forward {} start", domain);
+            if opt.forwarded_headers {
+                let visitor = entry.visitor.ip();
+                let last = LastActive::new();
+                let mut conn = Active::new(conn, last.clone());
+                let copy = copy_http(stream, &mut conn, buf.to_vec(), |head| {
+                    set_forwarded(head, visitor, "https")
+                });
                 tokio::select! {
-                    conn = receiver => {
-                        let mut conn = conn.map_err(err!())?;
-                        conn.write_all(&result.buf).await.map_err(err!())?;
-                        debug!("forward {} start", &result.domain);
-                        copy_bidirectional(&mut stream, &mut conn).await.map_err(err!("forward {}", &result.domain))?;
-                        debug!("forward {} end", &result.domain);
+                    re = copy => {
+                        if re.is_err() {
+                            entry.outcome = Outcome::ForwardError;
+                        }
+                        re.ctx("domain", domain)?
                     }
-                    _ = sleep(Duration::from_secs(15)) => {
-                        error!("{} timeout", result.domain);
-                        GATEWAY_TIMEOUT.send(&mut stream).await?;
-                        shared.conn.remove(&key);
-                        let _ = stream.shutdown().await;
+                    _ = last.idle(timeouts.idle) => {
+                        debug!("forward {} idle timeout", domain);
+                        entry.outcome = Outcome::IdleTimeout;
                     }
                 }
             } else {
-                error!("no client found for {}", result.domain);
-                BAD_GATEWAY.send(&mut stream).await?;
-                let _ = stream.shutdown().await;
+                conn.write_all(buf).await.map_err(err!())?;
+                let re = copy_bidirectional(stream, &mut conn, timeouts.idle).await;
+                if let Err(e) = &re {
+                    entry.outcome = copy_outcome(e);
+                }
+                re.map_err(err!("forward {}", domain))?;
             }
+            debug!("forward {} end", domain);
         }
-        _ = sleep(Duration::from_secs(30)) => {
-            let _ = stream.shutdown().await;
-            error!("{} parse domain timeout", addr);
+        Some(Err(rejection)) => {
+            log_rejection(domain, &rejection);
+            let (status, outcome) = rejection_status(&rejection);
+            metrics.error(domain, status);
+            entry.outcome = outcome;
+            reply.send_rejection(stream, &rejection).await?;
+        }
+        None => {
+            error!("{} timeout", domain);
+            metrics.error(domain, GATEWAY_TIMEOUT);
+            entry.outcome = Outcome::GatewayTimeout;
+            reply.send(stream, GATEWAY_TIMEOUT, &[]).await?;
         }
     }
-
     Ok(())
 }
 
-fn make_key(domain: &str) -> Vec<u8> {
-    let mut md5 = Md5::new();
-    md5.update(domain.as_bytes());
-    if let Ok(d) = SystemTime::now().duration_since(UNIX_EPOCH) {
-        md5.update(d.as_secs().to_be_bytes());
+// 转发过程中出错时访问日志中的结果, copy_bidirectional 空闲超时返回 TimedOut
+fn copy_outcome(e: &io::Error) -> Outcome {
+    match e.kind() {
+        ErrorKind::TimedOut => Outcome::IdleTimeout,
+        _ => Outcome::ForwardError,
+    }
+}
+
+fn log_rejection(target: &str, rejection: &Rejection) {
+    match rejection {
+        Rejection::Unreachable(reason) => error!("{} rejected: {}", target, reason),
+        Rejection::NotFound => error!("{} not found in client", target),
+        Rejection::Busy => warn!("{} too many pending connections", target),
+    }
+}
+
+// 转发失败时发给访问者的状态码和访问日志中的结果
+fn rejection_status(rejection: &Rejection) -> (Status, Outcome) {
+    match rejection {
+        Rejection::Unreachable(_) => (BAD_GATEWAY, Outcome::BadGateway),
+        Rejection::NotFound => (NOT_FOUND, Outcome::NotFound),
+        Rejection::Busy => (SERVICE_UNAVAILABLE, Outcome::Unavailable),
+    }
+}
+
+// 向访问者发送错误响应并关闭连接, 配置了错误页面时带页面内容, 响应头中带请求 ID
+struct Reply<'a> {
+    domain: &'a str,
+    request_id: &'a str,
+    accept: Option<&'a str>, // 访问者的 Accept 头
+    rules: &'a Rules,
+}
+
+impl Reply<'_> {
+    async fn send(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        status: Status,
+        headers: &[(&str, String)],
+    ) -> crate::Result<()> {
+        self.respond(stream, None, status, headers, None).await
+    }
+
+    // 使用名为 page 的页面, 没有时使用状态码对应的页面
+    async fn send_page(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        page: &str,
+        status: Status,
+        headers: &[(&str, String)],
+    ) -> crate::Result<()> {
+        self.respond(stream, Some(page), status, headers, None)
+            .await
+    }
+
+    // 转发失败的响应. 无法连接的原因可能包含客户端内网的地址, 只写入服务端日志,
+    // 没有错误页面时发送固定的内容
+    async fn send_rejection(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        rejection: &Rejection,
+    ) -> crate::Result<()> {
+        let (status, _) = rejection_status(rejection);
+        let text = match rejection {
+            Rejection::Unreachable(_) => Some(format!(
+                "upstream unreachable (request id {})\n",
+                self.request_id
+            )),
+            _ => None,
+        };
+        self.respond(stream, None, status, &[], text.as_deref())
+            .await
+    }
+
+    // text 为没有错误页面时的纯文本内容
+    async fn respond(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        page: Option<&str>,
+        status: Status,
+        headers: &[(&str, String)],
+        text: Option<&str>,
+    ) -> crate::Result<()> {
+        let mut headers: Vec<_> = headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        headers.push(("x-request-id", self.request_id));
+        let page =
+            self.rules
+                .error_pages
+                .render(page, status, self.domain, self.request_id, self.accept);
+        match (page, text) {
+            (Some((content_type, body)), _) => {
+                status
+                    .send_body_with_headers(stream, &headers, content_type, &body)
+                    .await?
+            }
+            (None, Some(text)) => {
+                let content_type = "text/plain; charset=utf-8";
+                status
+                    .send_body_with_headers(stream, &headers, content_type, text)
+                    .await?
+            }
+            (None, None) => status.send_with_headers(stream, &headers).await?,
+        }
+        let _ = stream.shutdown().await;
+        Ok(())
+    }
+}
+
+// 访问者请求的 ID, 记录在访问日志中, 并通过错误响应的 X-Request-Id 头返回
+fn make_request_id() -> String {
+    format!("{:016x}", OsRng.next_u64())
+}
+
+// 来自信任地址段的连接先读取 PROXY 头, 返回 (访问者地址, 访问者连接的服务端地址)
+async fn accept_proxy(
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+    trusted: &[Cidr],
+) -> crate::Result<(SocketAddr, SocketAddr)> {
+    let local = stream.local_addr().map_err(err!())?;
+    if !cidr::contains(trusted, addr.ip()) {
+        return Ok((addr, local));
+    }
+    match timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(stream)).await {
+        Ok(Ok(Some(addrs))) => {
+            debug!("{} is proxied by {}", addrs.0, addr);
+            Ok(addrs)
+        }
+        Ok(Ok(None)) => Ok((addr, local)),
+        Ok(Err(e)) => Err(e).ctx("peer", addr),
+        Err(e) => Err(e)
+            .map_err(err!("read proxy header timeout"))
+            .ctx("peer", addr),
+    }
+}
+
+// 通知客户端建立到 target 的转发连接, 等待客户端应答, 超时返回 None
+// addrs 为 (访问者地址, 访问者连接的服务端地址)
+async fn request_conn(
+    client: &Session,
+    target: Target,
+    addrs: (SocketAddr, SocketAddr),
+    timeout: Duration,
+    shared: &Shared,
+) -> crate::Result<Option<Conn>> {
+    let key = make_key();
+    let mut pending = match shared.conn.add(key.clone(), client, timeout) {
+        Some(pending) => pending,
+        None => return Ok(Some(Err(Rejection::Busy))),
+    };
+    let request = Request::new(key, target.clone(), addrs.0, addrs.1);
+    match client.tx.try_send(Protocol::Request(request)) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => return Ok(Some(Err(Rejection::Busy))),
+        Err(e) => return Err(e).map_err(err!()),
+    }
+
+    let start = Instant::now();
+    let histogram = shared.metrics.response_duration.get(&[&target.to_string()]);
+    tokio::select! {
+        conn = &mut pending.rx => {
+            histogram.observe(start.elapsed().as_secs_f64());
+            match conn {
+                Ok(conn) => Ok(Some(conn)),
+                Err(_) => Ok(Some(Err(Rejection::Unreachable("client disconnected".to_string())))),
+            }
+        }
+        _ = sleep(timeout) => Ok(None),
+    }
+}
+
+// 转发连接的 key, 直接取自系统的安全随机数, 无法预测
+fn make_key() -> Vec<u8> {
+    let mut key = vec![0; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// 客户端证书, 没有证书时为空
+fn peer_cert(stream: &TlsStream<TcpStream>) -> Certificate {
+    match stream.get_ref().1.peer_certificates() {
+        Some(certs) if !certs.is_empty() => certs[0].clone(),
+        _ => Certificate(Vec::new()),
     }
-    let r: u64 = random();
-    md5.update(r.to_be_bytes());
-    md5.finalize().to_vec()
 }
 
 fn create_client_acceptor(key: &str, cert: &str) -> crate::Result<TlsAcceptor> {
@@ -305,3 +1406,54 @@ fn create_http_acceptor(key: &str, cert: &str) -> crate::Result<TlsAcceptor> {
         .map_err(err!())?;
     Ok(TlsAcceptor::from(Arc::new(config)))
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::*;
+
+    async fn send_rejection(rules: &Rules, rejection: Rejection) -> String {
+        let reply = Reply {
+            domain: "a.com",
+            request_id: "0123456789abcdef",
+            accept: None,
+            rules,
+        };
+        let (mut visitor, mut server) = duplex(4096);
+        reply.send_rejection(&mut server, &rejection).await.unwrap();
+        drop(server);
+        let mut s = String::new();
+        visitor.read_to_string(&mut s).await.unwrap();
+        s
+    }
+
+    #[test]
+    fn rejection_statuses() {
+        let status = |rejection| rejection_status(&rejection).0.code();
+        assert_eq!(status(Rejection::Unreachable("refused".to_string())), 502);
+        assert_eq!(status(Rejection::NotFound), 404);
+        assert_eq!(status(Rejection::Busy), 503);
+    }
+
+    #[tokio::test]
+    async fn unreachable_hides_reason() {
+        let rules = Rules::default();
+        let reason = "cannot connect to 192.168.1.10:8080".to_string();
+        let response = send_rejection(&rules, Rejection::Unreachable(reason)).await;
+        let body = "upstream unreachable (request id 0123456789abcdef)\n";
+        assert_eq!(
+            response,
+            format!(
+                "HTTP/1.1 502 Bad Gateway\r\nx-request-id: 0123456789abcdef\r\n\
+                 content-type: text/plain; charset=utf-8\r\ncontent-length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        );
+
+        let response = send_rejection(&rules, Rejection::Busy).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("content-length: 0\r\n\r\n"));
+    }
+}
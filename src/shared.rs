@@ -1,86 +1,835 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Local};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{self, UnboundedSender};
 use tokio::sync::oneshot::{self, Receiver, Sender};
+use tokio::sync::Notify;
+use tokio::time::sleep_until;
+use tokio_rustls::rustls::Certificate;
 use tokio_rustls::server::TlsStream;
 
-use crate::protocol::Request;
+use crate::access_log::AccessLog;
+use crate::acl::Acl;
+use crate::auth::Auth;
+use crate::error_page::ErrorPages;
+use crate::http::Status;
+use crate::limit::Limits;
+use crate::metrics::{Counter, Encoder, Family, Gauge, Histogram};
+use crate::protocol::Protocol;
+use crate::util::cert_subject;
+
+// 每个客户端的消息队列长度, 队列满时新的转发请求返回 503, UDP 数据报被丢弃
+const CLIENT_QUEUE_SIZE: usize = 1024;
 
 // 共享状态
 #[derive(Clone)]
 pub struct Shared {
     pub client: ClientChannel,
     pub conn: ConnChannel,
+    pub metrics: Arc<Metrics>,
+    pub access_log: AccessLog,
+    pub limits: Arc<Limits>,
+    pub rules: RuleChannel,
+    pub reload: UnboundedSender<ReloadReply>, // 通知重新加载配置
+}
+
+// 服务端配置的访问规则, 重新加载配置时整体替换
+#[derive(Default)]
+pub struct Rules {
+    pub auth: HashMap<String, Arc<Auth>>, // 各域名的访问认证
+    pub acl: Acl,                         // 访问者地址的访问控制
+    pub error_pages: ErrorPages,          // 发给访问者的错误页面
+}
+
+#[derive(Clone, Default)]
+pub struct RuleChannel(Arc<RwLock<Arc<Rules>>>);
+
+impl RuleChannel {
+    pub fn get(&self) -> Arc<Rules> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, rules: Rules) {
+        *self.0.write().unwrap() = Arc::new(rules);
+    }
 }
 
+// 用来返回重新加载的结果
+pub type ReloadReply = Sender<Result<(), String>>;
+
 impl Shared {
-    pub fn new() -> Self {
+    pub fn new(
+        access_log: AccessLog,
+        limits: Limits,
+        conn: ConnChannel,
+        reload: UnboundedSender<ReloadReply>,
+    ) -> Self {
         Self {
             client: ClientChannel::new(),
-            conn: ConnChannel::new(),
+            conn,
+            metrics: Arc::new(Metrics::new()),
+            access_log,
+            limits: Arc::new(limits),
+            rules: RuleChannel::default(),
+            reload,
         }
     }
+
+    // 生成 Prometheus 格式的指标
+    pub fn render_metrics(&self) -> String {
+        let m = &self.metrics;
+        let mut e = Encoder::default();
+        e.gauge("http_forward_clients", "Connected clients", m.clients.get());
+        e.gauge(
+            "http_forward_connections",
+            "Open visitor connections",
+            m.connections.get(),
+        );
+        e.gauge(
+            "http_forward_pending_connections",
+            "Visitor connections waiting for a client data connection",
+            self.conn.len() as i64,
+        );
+        e.gauge(
+            "http_forward_domains",
+            "Registered domains",
+            self.client.len() as i64,
+        );
+        e.gauge_family(
+            "http_forward_active_connections",
+            "Active forwarded connections",
+            &m.active,
+        );
+        e.counter_family(
+            "http_forward_received_bytes_total",
+            "Bytes received from visitors",
+            &m.received_bytes,
+        );
+        e.counter_family(
+            "http_forward_sent_bytes_total",
+            "Bytes sent to visitors",
+            &m.sent_bytes,
+        );
+        e.counter_family(
+            "http_forward_errors_total",
+            "Error responses sent to visitors",
+            &m.errors,
+        );
+        e.counter(
+            "http_forward_unknown_domain_total",
+            "Requests for unknown domains",
+            &m.unknown_domains,
+        );
+        e.counter_family(
+            "http_forward_offline_total",
+            "Requests for known domains whose client is offline",
+            &m.offline,
+        );
+        e.counter(
+            "http_forward_parse_timeout_total",
+            "Host header parse timeouts",
+            &m.parse_timeouts,
+        );
+        e.counter_family(
+            "http_forward_tls_handshake_failures_total",
+            "TLS handshake failures",
+            &m.tls_failures,
+        );
+        e.histogram_family(
+            "http_forward_client_response_seconds",
+            "Time from Request to the matching client reply",
+            &m.response_duration,
+        );
+        e.finish()
+    }
+}
+
+// 服务端指标
+pub struct Metrics {
+    pub clients: Arc<Gauge>,                  // 已注册的客户端数
+    pub connections: Arc<Gauge>,              // 访问者的连接数, 包括等待解析和等待客户端应答的连接
+    pub active: Family<Gauge>,                // 各转发目标正在转发的连接数
+    pub received_bytes: Family<Counter>,      // 各转发目标从访问者收到的字节数
+    pub sent_bytes: Family<Counter>,          // 各转发目标发给访问者的字节数
+    pub errors: Family<Counter>,              // 各转发目标的错误响应数, 按状态码区分
+    pub unknown_domains: Counter,             // 未知域名的请求数
+    pub offline: Family<Counter>,             // 各已知域名在客户端未连接时的请求数
+    pub parse_timeouts: Counter,              // 解析 Host 头超时数
+    pub tls_failures: Family<Counter>,        // TLS 握手失败数, 按监听端口区分
+    pub response_duration: Family<Histogram>, // 发出转发请求到客户端应答的耗时
 }
 
-// 客户端集合, key 为域名, value 用来发送转发请求
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            clients: Arc::new(Gauge::default()),
+            connections: Arc::new(Gauge::default()),
+            active: Family::new(&["domain"]),
+            received_bytes: Family::new(&["domain"]),
+            sent_bytes: Family::new(&["domain"]),
+            errors: Family::new(&["domain", "status"]),
+            unknown_domains: Counter::default(),
+            offline: Family::new(&["domain"]),
+            parse_timeouts: Counter::default(),
+            tls_failures: Family::new(&["listener"]),
+            response_duration: Family::new(&["domain"]),
+        }
+    }
+
+    // 记录发给访问者的错误响应
+    pub fn error(&self, domain: &str, status: Status) {
+        self.errors.get(&[domain, &status.code().to_string()]).inc();
+    }
+}
+
+// 已注册的客户端
+pub struct Session {
+    pub id: u64,                                   // 会话标识
+    pub addr: SocketAddr,                          // 客户端地址
+    pub subject: String,                           // 客户端证书 subject
+    pub cert: Arc<[u8]>,                           // 客户端证书, 用来校验转发连接
+    pub domains: Vec<String>,                      // 转发的域名
+    pub ports: Vec<u16>,                           // 转发的 TCP 端口
+    pub udp_ports: Vec<u16>,                       // 转发的 UDP 端口
+    pub auth: HashMap<String, Arc<Auth>>,          // 客户端要求的各域名访问认证
+    pub connected_at: DateTime<Local>,             // 注册时间
+    pub last_ping: Mutex<Option<DateTime<Local>>>, // 最后一次收到 Ping 的时间
+    pub tx: mpsc::Sender<Protocol>,                // 用来向客户端发送消息
+    pub kick: Notify,                              // 通知断开该客户端
+}
+
+#[derive(Default)]
+struct Clients {
+    domains: HashMap<String, Arc<Session>>, // key 为域名, value 为处理该域名的客户端
+    sessions: BTreeMap<u64, Arc<Session>>,  // key 为会话标识
+    blocked: BTreeSet<String>,              // 被禁止的域名
+    draining: BTreeSet<String>,             // 不再接受新连接的域名, 客户端断开后清除
+    reserved: HashMap<String, Reservation>, // 客户端断开后在宽限期内保留的域名
+    going_away: bool,                       // 服务端即将退出, 不再接受注册
+    next_id: u64,
+}
+
+impl Clients {
+    // 移除仍由 session 处理的域名, 已被其他客户端注册的域名不受影响
+    fn release(&mut self, session: &Session, grace: impl Fn(&str) -> Duration) {
+        for d in &session.domains {
+            match self.domains.get(d) {
+                Some(v) if v.id == session.id => {}
+                _ => continue,
+            }
+            self.domains.remove(d);
+            self.draining.remove(d);
+            let grace = grace(d);
+            if !grace.is_zero() && !self.going_away {
+                let reservation = Reservation {
+                    subject: session.subject.clone(),
+                    expires: Instant::now() + grace,
+                    registered: Arc::new(Notify::new()),
+                };
+                self.reserved.insert(d.clone(), reservation);
+            }
+        }
+    }
+}
+
+// 客户端断开后保留的域名, 宽限期内只有证书 subject 相同的客户端能注册
+struct Reservation {
+    subject: String,
+    expires: Instant,
+    registered: Arc<Notify>, // 客户端重新注册时通知等待的访问者
+}
+
+// 查找域名对应的客户端的结果
+pub enum Lookup {
+    Found(Arc<Session>),
+    Expired, // 等待到宽限期结束, 客户端没有重新注册
+    NotFound,
+}
+
+// 客户端集合
 #[derive(Clone)]
-pub struct ClientChannel(Arc<RwLock<HashMap<String, UnboundedSender<Request>>>>);
+pub struct ClientChannel(Arc<RwLock<Clients>>);
 
 impl ClientChannel {
     pub fn new() -> Self {
-        Self(Arc::new(RwLock::new(HashMap::new())))
+        Self(Arc::new(RwLock::new(Clients::default())))
     }
 
-    // 是否已存在处理 domains 中任意一个域名的客户端
-    pub fn exists(&self, domains: &[String]) -> bool {
-        let map = self.0.read().unwrap();
-        for v in domains {
-            if map.contains_key(v) {
-                return true;
+    // 是否已存在证书 subject 不同的客户端处理 domains 中任意一个域名, 或者其中有被禁止的域名,
+    // 或者有被证书 subject 不同的客户端保留的域名
+    pub fn exists(&self, domains: &[String], subject: &str) -> bool {
+        let clients = self.0.read().unwrap();
+        let now = Instant::now();
+        domains.iter().any(|v| {
+            clients.domains.get(v).is_some_and(|s| s.subject != subject)
+                || clients.blocked.contains(v)
+                || clients
+                    .reserved
+                    .get(v)
+                    .is_some_and(|r| r.subject != subject && r.expires > now)
+        })
+    }
+
+    // 是否有处理 domain 的客户端, 或者 domain 在宽限期内保留
+    pub fn contains(&self, domain: &str) -> bool {
+        let clients = self.0.read().unwrap();
+        clients.domains.contains_key(domain)
+            || clients
+                .reserved
+                .get(domain)
+                .is_some_and(|r| r.expires > Instant::now())
+    }
+
+    // 证书 subject 相同, 处理 domains 中任意一个域名的客户端. 客户端网络中断后重连时,
+    // 旧的会话可能还没有超时断开, 由新的会话取代
+    pub fn stale(&self, domains: &[String], subject: &str) -> Vec<Arc<Session>> {
+        let clients = self.0.read().unwrap();
+        let mut sessions: Vec<Arc<Session>> = domains
+            .iter()
+            .filter_map(|v| clients.domains.get(v))
+            .filter(|s| s.subject == subject)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions.dedup_by_key(|s| s.id);
+        sessions
+    }
+
+    // 已注册的域名数
+    pub fn len(&self) -> usize {
+        self.0.read().unwrap().domains.len()
+    }
+
+    pub fn add(
+        &self,
+        addr: SocketAddr,
+        cert: Certificate,
+        domains: Vec<String>,
+        ports: Vec<u16>,
+        udp_ports: Vec<u16>,
+        auth: Vec<(String, Auth)>,
+    ) -> (Arc<Session>, mpsc::Receiver<Protocol>) {
+        let (tx, rx) = mpsc::channel(CLIENT_QUEUE_SIZE);
+        let session = Session {
+            id: 0,
+            addr,
+            subject: cert_subject(&cert),
+            cert: cert.0.into(),
+            domains,
+            ports,
+            udp_ports,
+            auth: auth.into_iter().map(|(k, v)| (k, Arc::new(v))).collect(),
+            connected_at: Local::now(),
+            last_ping: Mutex::new(None),
+            tx,
+            kick: Notify::new(),
+        };
+        (self.insert(session), rx)
+    }
+
+    // 分配会话标识, 注册 session 处理的域名
+    fn insert(&self, mut session: Session) -> Arc<Session> {
+        let mut clients = self.0.write().unwrap();
+        clients.next_id += 1;
+        session.id = clients.next_id;
+        let session = Arc::new(session);
+        let now = Instant::now();
+        clients.reserved.retain(|_, v| v.expires > now);
+        for d in &session.domains {
+            // 取代旧的会话时清除其排空状态
+            if clients.domains.insert(d.clone(), session.clone()).is_some() {
+                clients.draining.remove(d);
+            }
+            if let Some(reservation) = clients.reserved.remove(d) {
+                reservation.registered.notify_waiters();
+            }
+        }
+        clients.sessions.insert(session.id, session.clone());
+        if clients.going_away {
+            let _ = session.tx.try_send(Protocol::GoingAway);
+        }
+        session
+    }
+
+    // grace 为各域名的宽限期, 不为 0 时在宽限期内保留域名, 等待客户端重新注册
+    pub fn remove(&self, session: &Session, grace: impl Fn(&str) -> Duration) {
+        let mut clients = self.0.write().unwrap();
+        clients.release(session, grace);
+        clients.sessions.remove(&session.id);
+    }
+
+    // 客户端即将断开, 不再向其转发新连接, 会话保留到连接关闭. grace 同 remove
+    pub fn retire(&self, session: &Session, grace: impl Fn(&str) -> Duration) {
+        self.0.write().unwrap().release(session, grace);
+    }
+
+    // 查找处理 domain 的客户端, 域名在宽限期内时等待客户端重新注册
+    pub async fn lookup(&self, domain: &str) -> Lookup {
+        let mut waited = false;
+        loop {
+            let (registered, expires) = {
+                let clients = self.0.read().unwrap();
+                if clients.blocked.contains(domain) {
+                    return Lookup::NotFound;
+                }
+                if let Some(session) = clients.domains.get(domain) {
+                    return Lookup::Found(session.clone());
+                }
+                match clients.reserved.get(domain) {
+                    Some(r) if r.expires > Instant::now() => (r.registered.clone(), r.expires),
+                    _ if waited => return Lookup::Expired,
+                    _ => return Lookup::NotFound,
+                }
+            };
+            // notified 创建之后的通知不会错过, 但释放锁后客户端可能已重新注册, 需再检查一次
+            let notified = registered.notified();
+            if self.0.read().unwrap().domains.contains_key(domain) {
+                continue;
+            }
+            waited = true;
+            tokio::select! {
+                _ = notified => {}
+                _ = sleep_until(expires.into()) => {}
             }
         }
-        false
     }
 
-    pub fn get(&self, domain: &str) -> Option<UnboundedSender<Request>> {
-        self.0.read().unwrap().get(domain).map(Clone::clone)
+    // 所有已注册的客户端
+    pub fn sessions(&self) -> Vec<Arc<Session>> {
+        self.0.read().unwrap().sessions.values().cloned().collect()
+    }
+
+    pub fn session(&self, id: u64) -> Option<Arc<Session>> {
+        self.0.read().unwrap().sessions.get(&id).cloned()
+    }
+
+    // 禁止 domain, 已注册的客户端保持连接, 但不再转发该域名的请求
+    pub fn block(&self, domain: &str) -> bool {
+        self.0.write().unwrap().blocked.insert(domain.to_string())
+    }
+
+    pub fn unblock(&self, domain: &str) -> bool {
+        self.0.write().unwrap().blocked.remove(domain)
+    }
+
+    pub fn is_blocked(&self, domain: &str) -> bool {
+        self.0.read().unwrap().blocked.contains(domain)
+    }
+
+    pub fn blocked(&self) -> Vec<String> {
+        self.0.read().unwrap().blocked.iter().cloned().collect()
     }
 
-    pub fn add(&self, domains: Vec<String>) -> UnboundedReceiver<Request> {
-        let (tx, rx) = unbounded_channel();
-        let mut map = self.0.write().unwrap();
-        for d in domains {
-            map.insert(d, tx.clone());
+    // 排空 domain, 正在转发的连接不受影响, 新连接返回 503, 域名未注册时返回 false
+    pub fn drain(&self, domain: &str) -> bool {
+        let mut clients = self.0.write().unwrap();
+        if !clients.domains.contains_key(domain) {
+            return false;
         }
-        rx
+        clients.draining.insert(domain.to_string());
+        true
+    }
+
+    pub fn undrain(&self, domain: &str) -> bool {
+        self.0.write().unwrap().draining.remove(domain)
     }
 
-    pub fn remove(&self, domains: &[String]) {
-        let mut map = self.0.write().unwrap();
-        for d in domains {
-            map.remove(d);
+    pub fn is_draining(&self, domain: &str) -> bool {
+        self.0.read().unwrap().draining.contains(domain)
+    }
+
+    // 通知所有客户端服务端即将退出, 之后不再接受注册
+    pub fn going_away(&self) {
+        let mut clients = self.0.write().unwrap();
+        clients.going_away = true;
+        for session in clients.sessions.values() {
+            let _ = session.tx.try_send(Protocol::GoingAway);
         }
     }
+
+    pub fn is_going_away(&self) -> bool {
+        self.0.read().unwrap().going_away
+    }
 }
 
-// 待转发连接集合, key 为标识, value 用来发送目标连接
+// 转发失败的原因
+pub enum Rejection {
+    Unreachable(String), // 无法连接目的地址, 或客户端已断开
+    NotFound,            // 客户端没有该转发目标的配置
+    Busy,                // 等待应答的连接数超过上限, 或客户端消息队列已满
+}
+
+// 客户端对转发请求的应答, 成功为目标连接, 失败为原因
+pub type Conn = Result<TlsStream<TcpStream>, Rejection>;
+
+type ConnSender = Sender<Conn>;
+
+// 待转发连接集合, 总数和每个客户端的数量有上限
 #[derive(Clone)]
-pub struct ConnChannel(Arc<Mutex<HashMap<Vec<u8>, Sender<TlsStream<TcpStream>>>>>);
+pub struct ConnChannel(Arc<Mutex<Pending>>);
+
+struct Pending {
+    conns: HashMap<Vec<u8>, PendingEntry>, // key 为标识
+    clients: HashMap<u64, usize>,          // 各客户端等待应答的连接数
+    max: usize,
+    max_per_client: usize,
+}
+
+struct PendingEntry {
+    client: u64,      // 收到转发请求的客户端会话标识
+    cert: Arc<[u8]>,  // 该客户端的证书, 只有持有相同证书的连接能领取
+    expires: Instant, // 过期时间
+    tx: ConnSender,   // 用来发送客户端的应答
+}
+
+impl Pending {
+    fn remove(&mut self, key: &[u8]) -> Option<ConnSender> {
+        let PendingEntry { client, tx, .. } = self.conns.remove(key)?;
+        if let Some(n) = self.clients.get_mut(&client) {
+            *n -= 1;
+            if *n == 0 {
+                self.clients.remove(&client);
+            }
+        }
+        Some(tx)
+    }
+}
 
 impl ConnChannel {
-    pub fn new() -> Self {
-        Self(Arc::new(Mutex::new(HashMap::new())))
+    pub fn new(max: usize, max_per_client: usize) -> Self {
+        Self(Arc::new(Mutex::new(Pending {
+            conns: HashMap::new(),
+            clients: HashMap::new(),
+            max,
+            max_per_client,
+        })))
     }
 
-    pub fn add(&self, key: Vec<u8>) -> Receiver<TlsStream<TcpStream>> {
+    // 超过上限时返回 None, 返回值销毁时移除 key, 不会遗留过期的 key
+    // ttl 为 key 的有效期, 与等待客户端应答的时间相同
+    pub fn add(&self, key: Vec<u8>, client: &Session, ttl: Duration) -> Option<PendingConn> {
+        let mut pending = self.0.lock().unwrap();
+        let n = pending.clients.get(&client.id).copied().unwrap_or(0);
+        if pending.conns.len() >= pending.max || n >= pending.max_per_client {
+            return None;
+        }
         let (tx, rx) = oneshot::channel();
-        self.0.lock().unwrap().insert(key, tx);
-        rx
+        let entry = PendingEntry {
+            client: client.id,
+            cert: client.cert.clone(),
+            expires: Instant::now() + ttl,
+            tx,
+        };
+        pending.conns.insert(key.clone(), entry);
+        *pending.clients.entry(client.id).or_insert(0) += 1;
+        Some(PendingConn {
+            channel: self.clone(),
+            key,
+            rx,
+        })
+    }
+
+    // 客户端用 key 领取待转发连接, 只有收到转发请求的客户端 (证书相同) 能领取,
+    // key 只能使用一次, 过期后失效. 领取失败返回 None
+    pub fn claim(&self, key: &[u8], cert: &[u8]) -> Option<ConnSender> {
+        let mut pending = self.0.lock().unwrap();
+        let entry = pending.conns.get(key)?;
+        if *entry.cert != *cert {
+            return None;
+        }
+        if entry.expires < Instant::now() {
+            pending.remove(key);
+            return None;
+        }
+        pending.remove(key)
     }
 
-    pub fn remove(&self, key: &[u8]) -> Option<Sender<TlsStream<TcpStream>>> {
+    fn remove(&self, key: &[u8]) -> Option<ConnSender> {
         self.0.lock().unwrap().remove(key)
     }
+
+    // 客户端断开后移除其等待应答的连接, 对应的访问者立即收到错误响应
+    pub fn remove_client(&self, client: u64) {
+        let mut pending = self.0.lock().unwrap();
+        let keys: Vec<_> = pending
+            .conns
+            .iter()
+            .filter(|(_, v)| v.client == client)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            pending.remove(&key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().conns.len()
+    }
+
+    // 客户端等待应答的连接数
+    pub fn count(&self, client: u64) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .clients
+            .get(&client)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+// 等待客户端应答的转发连接
+pub struct PendingConn {
+    channel: ConnChannel,
+    key: Vec<u8>,
+    pub rx: Receiver<Conn>,
+}
+
+impl Drop for PendingConn {
+    fn drop(&mut self) {
+        self.channel.remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn session(clients: &ClientChannel, cert: &[u8]) -> Arc<Session> {
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let cert = Certificate(cert.to_vec());
+        let (session, _) = clients.add(addr, cert, Vec::new(), vec![2222], Vec::new(), Vec::new());
+        session
+    }
+
+    // 证书 subject 为 subject, 处理 domain 的客户端
+    fn register(clients: &ClientChannel, subject: &str, domain: &str) -> Arc<Session> {
+        let (tx, _) = mpsc::channel(1);
+        clients.insert(Session {
+            id: 0,
+            addr: "127.0.0.1:50000".parse().unwrap(),
+            subject: subject.to_string(),
+            cert: subject.as_bytes().into(),
+            domains: vec![domain.to_string()],
+            ports: Vec::new(),
+            udp_ports: Vec::new(),
+            auth: HashMap::new(),
+            connected_at: Local::now(),
+            last_ping: Mutex::new(None),
+            tx,
+            kick: Notify::new(),
+        })
+    }
+
+    fn domains(domain: &str) -> Vec<String> {
+        vec![domain.to_string()]
+    }
+
+    #[test]
+    fn reserved_for_same_subject() {
+        let clients = ClientChannel::new();
+        let session = register(&clients, "CN=a", "a.com");
+        clients.remove(&session, |_| Duration::from_secs(10));
+        assert!(clients.contains("a.com"));
+        assert!(!clients.exists(&domains("a.com"), "CN=a"));
+        assert!(clients.exists(&domains("a.com"), "CN=b"));
+        assert!(!clients.exists(&domains("b.com"), "CN=b"));
+    }
+
+    #[test]
+    fn reservation_expires() {
+        let clients = ClientChannel::new();
+        let session = register(&clients, "CN=a", "a.com");
+        clients.remove(&session, |_| Duration::from_millis(1));
+        sleep(Duration::from_millis(5));
+        assert!(!clients.contains("a.com"));
+        assert!(!clients.exists(&domains("a.com"), "CN=b"));
+    }
+
+    #[test]
+    fn no_grace_period() {
+        let clients = ClientChannel::new();
+        let session = register(&clients, "CN=a", "a.com");
+        clients.remove(&session, |_| Duration::ZERO);
+        assert!(!clients.contains("a.com"));
+        assert!(!clients.exists(&domains("a.com"), "CN=b"));
+    }
+
+    #[test]
+    fn blocked_domain_exists() {
+        let clients = ClientChannel::new();
+        clients.block("a.com");
+        assert!(clients.exists(&domains("a.com"), "CN=a"));
+    }
+
+    #[tokio::test]
+    async fn replace_stale_session() {
+        let clients = ClientChannel::new();
+        let old = register(&clients, "CN=a", "a.com");
+        assert!(clients.exists(&domains("a.com"), "CN=b"));
+        assert!(!clients.exists(&domains("a.com"), "CN=a"));
+        assert!(clients.stale(&domains("a.com"), "CN=b").is_empty());
+        let stale = clients.stale(&domains("a.com"), "CN=a");
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].id, old.id);
+
+        assert!(clients.drain("a.com"));
+        let new = register(&clients, "CN=a", "a.com");
+        assert!(!clients.is_draining("a.com"));
+        // 旧会话断开时不影响新会话注册的域名
+        clients.remove(&old, |_| Duration::from_secs(10));
+        assert!(clients.session(old.id).is_none());
+        assert!(matches!(
+            clients.lookup("a.com").await,
+            Lookup::Found(v) if v.id == new.id
+        ));
+    }
+
+    #[tokio::test]
+    async fn lookup_waits_for_register() {
+        let clients = ClientChannel::new();
+        let old = register(&clients, "CN=a", "a.com");
+        clients.remove(&old, |_| Duration::from_secs(10));
+        let lookup = tokio::spawn({
+            let clients = clients.clone();
+            async move { clients.lookup("a.com").await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let new = register(&clients, "CN=a", "a.com");
+        let found = tokio::time::timeout(Duration::from_secs(1), lookup).await;
+        assert!(matches!(found, Ok(Ok(Lookup::Found(v))) if v.id == new.id));
+    }
+
+    #[tokio::test]
+    async fn lookup_expired() {
+        let clients = ClientChannel::new();
+        let session = register(&clients, "CN=a", "a.com");
+        clients.remove(&session, |_| Duration::from_millis(50));
+        let start = Instant::now();
+        assert!(matches!(clients.lookup("a.com").await, Lookup::Expired));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(matches!(clients.lookup("a.com").await, Lookup::NotFound));
+    }
+
+    #[tokio::test]
+    async fn lookup_not_found() {
+        let clients = ClientChannel::new();
+        assert!(matches!(clients.lookup("a.com").await, Lookup::NotFound));
+        register(&clients, "CN=a", "a.com");
+        clients.block("a.com");
+        assert!(matches!(clients.lookup("a.com").await, Lookup::NotFound));
+    }
+
+    #[test]
+    fn claim_once() {
+        let conns = ConnChannel::new(10, 10);
+        let client = session(&ClientChannel::new(), b"cert");
+        let _pending = conns
+            .add(b"key".to_vec(), &client, Duration::from_secs(10))
+            .unwrap();
+        assert!(conns.claim(b"key", b"cert").is_some());
+        assert!(conns.claim(b"key", b"cert").is_none());
+        assert!(conns.claim(b"other", b"cert").is_none());
+        assert_eq!(conns.len(), 0);
+    }
+
+    #[test]
+    fn claim_with_other_cert() {
+        let conns = ConnChannel::new(10, 10);
+        let client = session(&ClientChannel::new(), b"cert");
+        let _pending = conns
+            .add(b"key".to_vec(), &client, Duration::from_secs(10))
+            .unwrap();
+        // 证书不同时不能领取, 也不影响原客户端领取
+        assert!(conns.claim(b"key", b"other").is_none());
+        assert_eq!(conns.len(), 1);
+        assert!(conns.claim(b"key", b"cert").is_some());
+    }
+
+    #[test]
+    fn claim_after_expiry() {
+        let conns = ConnChannel::new(10, 10);
+        let client = session(&ClientChannel::new(), b"cert");
+        let _pending = conns
+            .add(b"key".to_vec(), &client, Duration::from_millis(1))
+            .unwrap();
+        sleep(Duration::from_millis(5));
+        assert!(conns.claim(b"key", b"cert").is_none());
+        assert_eq!(conns.len(), 0);
+    }
+
+    #[test]
+    fn pending_drop_removes_key() {
+        let conns = ConnChannel::new(10, 10);
+        let client = session(&ClientChannel::new(), b"cert");
+        let pending = conns
+            .add(b"key".to_vec(), &client, Duration::from_secs(10))
+            .unwrap();
+        drop(pending);
+        assert_eq!(conns.len(), 0);
+        assert!(conns.claim(b"key", b"cert").is_none());
+    }
+
+    #[test]
+    fn max_pending_per_client() {
+        let conns = ConnChannel::new(10, 2);
+        let clients = ClientChannel::new();
+        let (a, b) = (session(&clients, b"a"), session(&clients, b"b"));
+        let ttl = Duration::from_secs(10);
+        let first = conns.add(b"1".to_vec(), &a, ttl).unwrap();
+        let _second = conns.add(b"2".to_vec(), &a, ttl).unwrap();
+        assert!(conns.add(b"3".to_vec(), &a, ttl).is_none());
+        // 其他客户端不受影响
+        let _other = conns.add(b"4".to_vec(), &b, ttl).unwrap();
+        assert_eq!(conns.count(a.id), 2);
+        drop(first);
+        assert_eq!(conns.count(a.id), 1);
+        assert!(conns.add(b"3".to_vec(), &a, ttl).is_some());
+    }
+
+    #[test]
+    fn max_pending() {
+        let conns = ConnChannel::new(2, 10);
+        let clients = ClientChannel::new();
+        let (a, b) = (session(&clients, b"a"), session(&clients, b"b"));
+        let ttl = Duration::from_secs(10);
+        let first = conns.add(b"1".to_vec(), &a, ttl).unwrap();
+        let _second = conns.add(b"2".to_vec(), &b, ttl).unwrap();
+        assert!(conns.add(b"3".to_vec(), &b, ttl).is_none());
+        drop(first);
+        assert_eq!(conns.len(), 1);
+        assert!(conns.add(b"3".to_vec(), &b, ttl).is_some());
+    }
+
+    #[test]
+    fn claim_releases_slot() {
+        let conns = ConnChannel::new(1, 1);
+        let client = session(&ClientChannel::new(), b"cert");
+        let ttl = Duration::from_secs(10);
+        let _pending = conns.add(b"1".to_vec(), &client, ttl).unwrap();
+        assert!(conns.add(b"2".to_vec(), &client, ttl).is_none());
+        assert!(conns.claim(b"1", b"cert").is_some());
+        assert!(conns.add(b"2".to_vec(), &client, ttl).is_some());
+    }
+
+    #[tokio::test]
+    async fn claimed_sender_reaches_visitor() {
+        let conns = ConnChannel::new(10, 10);
+        let client = session(&ClientChannel::new(), b"cert");
+        let mut pending = conns
+            .add(b"key".to_vec(), &client, Duration::from_secs(10))
+            .unwrap();
+        let sender = conns.claim(b"key", b"cert").unwrap();
+        let _ = sender.send(Err(Rejection::NotFound));
+        assert!(matches!(
+            (&mut pending.rx).await,
+            Ok(Err(Rejection::NotFound))
+        ));
+    }
 }
@@ -0,0 +1,220 @@
+use std::fmt::{Display, Formatter};
+use std::future::pending;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::sleep_until;
+
+// 按域名配置的秒数, 格式为 "[域名:]秒数", 不带域名时对所有域名生效.
+// TCP 转发的域名为 "tcp:端口"
+#[derive(Debug)]
+pub struct DomainSecs {
+    domain: Option<String>,
+    secs: u64,
+}
+
+#[derive(Debug)]
+pub struct InvalidDomainSecs;
+
+impl Display for InvalidDomainSecs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt("wrong format", f)
+    }
+}
+
+impl FromStr for DomainSecs {
+    type Err = InvalidDomainSecs;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (domain, secs) = match s.rsplit_once(':') {
+            Some(("", _)) => return Err(InvalidDomainSecs),
+            Some((domain, secs)) => (Some(domain.to_string()), secs),
+            None => (None, s),
+        };
+        let secs = secs.trim().parse().map_err(|_| InvalidDomainSecs)?;
+        Ok(Self { domain, secs })
+    }
+}
+
+impl DomainSecs {
+    pub fn secs(&self) -> u64 {
+        self.secs
+    }
+}
+
+// 域名的配置优先于全局配置, 同一域名配置多次时后面的生效, 都没有配置时使用 default
+pub fn domain_secs(list: &[DomainSecs], domain: &str, default: u64) -> Duration {
+    let find = |domain: Option<&str>| list.iter().rev().find(|v| v.domain.as_deref() == domain);
+    let secs = find(Some(domain))
+        .or_else(|| find(None))
+        .map_or(default, |v| v.secs);
+    Duration::from_secs(secs)
+}
+
+// 连接最后一次读写的时间, 用于空闲超时
+#[derive(Clone)]
+pub struct LastActive(Arc<(Instant, AtomicU64)>); // (起始时间, 最后读写距起始时间的毫秒数)
+
+impl LastActive {
+    pub fn new() -> Self {
+        Self(Arc::new((Instant::now(), AtomicU64::new(0))))
+    }
+
+    fn touch(&self) {
+        let elapsed = self.0 .0.elapsed().as_millis() as u64;
+        self.0 .1.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Instant {
+        self.0 .0 + Duration::from_millis(self.0 .1.load(Ordering::Relaxed))
+    }
+
+    // 超过 idle 没有读写时返回, idle 为 0 时不返回
+    pub async fn idle(&self, idle: Duration) {
+        if idle.is_zero() {
+            return pending().await;
+        }
+        loop {
+            let deadline = self.get() + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+// 读写时更新 LastActive 的连接
+pub struct Active<S> {
+    inner: S,
+    last: LastActive,
+}
+
+impl<S> Active<S> {
+    pub fn new(inner: S, last: LastActive) -> Self {
+        Self { inner, last }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Active<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.last.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Active<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = poll {
+            self.last.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// 与 tokio 的 copy_bidirectional 相同, 两个方向都超过 idle 没有数据时返回 TimedOut 错误,
+// idle 为 0 时不限制
+pub async fn copy_bidirectional<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle: Duration,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let last = LastActive::new();
+    let mut a = Active::new(a, last.clone());
+    tokio::select! {
+        re = tokio::io::copy_bidirectional(&mut a, b) => re,
+        _ = last.idle(idle) => Err(io::Error::new(ErrorKind::TimedOut, "idle timeout")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(list: &[&str]) -> Vec<DomainSecs> {
+        list.iter().map(|v| v.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_domain_secs() {
+        let v: DomainSecs = "30".parse().unwrap();
+        assert_eq!((v.domain, v.secs), (None, 30));
+        let v: DomainSecs = "a.com:30".parse().unwrap();
+        assert_eq!((v.domain.as_deref(), v.secs), (Some("a.com"), 30));
+        let v: DomainSecs = "tcp:2222:30".parse().unwrap();
+        assert_eq!((v.domain.as_deref(), v.secs), (Some("tcp:2222"), 30));
+        let v: DomainSecs = "a.com:0".parse().unwrap();
+        assert_eq!(v.secs(), 0);
+
+        for s in [
+            "",
+            "a.com",
+            "a.com:",
+            ":30",
+            "a.com:-1",
+            "a.com:1.5",
+            "tcp:2222:",
+        ] {
+            assert!(s.parse::<DomainSecs>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let secs = |list: &[DomainSecs], domain| domain_secs(list, domain, 60).as_secs();
+        assert_eq!(secs(&[], "a.com"), 60);
+
+        let list = parse(&["a.com:10", "30", "tcp:2222:5"]);
+        assert_eq!(secs(&list, "a.com"), 10);
+        assert_eq!(secs(&list, "b.com"), 30);
+        assert_eq!(secs(&list, "tcp:2222"), 5);
+        assert_eq!(secs(&list, "tcp:3333"), 30);
+
+        // 同一域名配置多次时后面的生效
+        let list = parse(&["a.com:10", "a.com:20", "tcp:2222:5", "tcp:2222:0"]);
+        assert_eq!(secs(&list, "a.com"), 20);
+        assert_eq!(secs(&list, "b.com"), 60);
+        assert_eq!(secs(&list, "tcp:2222"), 0);
+    }
+
+    #[tokio::test]
+    async fn idle() {
+        let last = LastActive::new();
+        let idle =
+            tokio::time::timeout(Duration::from_secs(1), last.idle(Duration::from_millis(20)));
+        assert!(idle.await.is_ok());
+        let idle = tokio::time::timeout(Duration::from_millis(20), last.idle(Duration::ZERO));
+        assert!(idle.await.is_err());
+    }
+}
@@ -1,15 +1,19 @@
 use std::env::{set_var, var};
 use std::fs::File;
-use std::io::{BufReader, Seek, SeekFrom};
+use std::io::{self, BufReader, ErrorKind, Seek, SeekFrom};
 
 use rustls_pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 use tokio_rustls::rustls::{Certificate, PrivateKey};
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 // 读取证书
 pub fn load_certs(path: &str) -> crate::Result<Vec<Certificate>> {
     let file = File::open(path).map_err(err!("cannot open {}", path))?;
     let certs = certs(&mut BufReader::new(file)).map_err(err!())?;
-    assert!(!certs.is_empty(), "no cert found in {}", path);
+    if certs.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "no cert found"))
+            .map_err(err!("{}", path));
+    }
     Ok(certs.into_iter().map(Certificate).collect::<Vec<_>>())
 }
 
@@ -22,10 +26,27 @@ pub fn load_key(path: &str) -> crate::Result<PrivateKey> {
         reader.seek(SeekFrom::Start(0)).map_err(err!())?;
         keys = pkcs8_private_keys(&mut reader).map_err(err!())?;
     }
-    assert!(!keys.is_empty(), "no key found in {}", path);
+    if keys.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "no key found"))
+            .map_err(err!("{}", path));
+    }
     Ok(PrivateKey(keys.pop().unwrap()))
 }
 
+// 证书的 subject, 格式如 "CN=foo, O=bar", 解析失败返回空字符串
+pub fn cert_subject(cert: &Certificate) -> String {
+    match X509Certificate::from_der(&cert.0) {
+        Ok((_, cert)) => cert.subject().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+// 比较 token, 耗时与内容无关
+pub fn eq_token(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn init_logger() {
     if var("RUST_LOG").is_err() {
         #[cfg(debug_assertions)]